@@ -1,9 +1,59 @@
-use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+use clap::{ArgGroup, Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+use crate::tasks::{Priority, SortKey};
+use crate::trust::TrustPolicy;
 
 #[derive(Parser)]
 #[command(name = "RustyTasks")]
 #[command(about = "A CRDT-powered CLI to-do list", long_about = None)]
 pub struct Cli {
+    /// Mark a task done once all of its subtasks are done
+    #[arg(long, global = true)]
+    pub auto_complete_parents: bool,
+
+    /// Directory holding task lists, identity and trusted peers
+    /// [default: the platform data directory, e.g. ~/.local/share/rustytasks]
+    #[arg(long, global = true, env = "RUSTYTASKS_HOME")]
+    pub data_dir: Option<PathBuf>,
+
+    /// Document file of the default list [default: autocommit_doc.automerge in the data directory]
+    #[arg(long, global = true)]
+    pub file: Option<PathBuf>,
+
+    /// Read settings from this file instead of the default config.toml
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Address to accept peers on, as host:port or [v6]:port; repeat to
+    /// listen on several [config: listen]
+    #[arg(long, global = true)]
+    pub listen: Vec<String>,
+
+    /// Port to use when connecting to a peer [config: connect_port]
+    #[arg(long, global = true)]
+    pub connect_port: Option<u16>,
+
+    /// Disable colored output [config: color]
+    #[arg(long, global = true)]
+    pub no_color: bool,
+
+    /// Hide the banner in interactive mode [config: banner]
+    #[arg(long, global = true)]
+    pub no_banner: bool,
+
+    /// Don't announce this device or look for peers on the LAN [config: discovery]
+    #[arg(long, global = true)]
+    pub no_discovery: bool,
+
+    /// What to do when an unknown peer connects [config: trust_policy]
+    #[arg(long, global = true, value_enum)]
+    pub trust_policy: Option<TrustPolicy>,
+
+    /// Act on this list (name or key) instead of the current one
+    #[arg(long, global = true)]
+    pub list: Option<String>,
+
     #[command(subcommand)]
     pub command: Option<Commands>,
 }
@@ -13,21 +63,269 @@ pub enum Commands {
     /// Start interactive mode
     Interactive,
 
+    /// Run headless as an always-on relay that keeps a replica of every
+    /// list peers share with it and passes changes on to peers that
+    /// connect later
+    Relay,
+
+    /// Run in the background, keeping lists in sync with peers; other
+    /// commands go through it while it runs
+    Daemon,
+
     /// Add a task
     Add {
         name: String,
+
+        /// Longer notes to attach to the task
+        #[arg(long)]
+        notes: Option<String>,
+
+        /// Due date, as YYYY-MM-DD, "YYYY-MM-DD HH:MM", today or tomorrow
+        #[arg(long)]
+        due: Option<String>,
+
+        #[arg(long, value_enum, default_value_t = Priority::Normal)]
+        priority: Priority,
+
+        /// Tag the task; repeat for several tags
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+
+        /// Add the task as a subtask of this task ID (or a unique prefix of it)
+        #[arg(long)]
+        parent: Option<String>,
     },
 
-    /// Remove a task by index
+    /// Remove a task by ID (or a unique prefix of it)
     Remove {
-        index: usize,
+        id: String,
     },
 
-    /// Mark a task as done by index
+    /// Mark a task as done by ID (or a unique prefix of it)
     Done {
-        index: usize,
+        id: String,
+    },
+
+    /// Mark a completed task as not done by ID (or a unique prefix of it)
+    Undone {
+        id: String,
+    },
+
+    /// Rename a task by ID (or a unique prefix of it)
+    Edit {
+        id: String,
+        name: String,
+    },
+
+    /// Add tags to a task
+    Tag {
+        id: String,
+        #[arg(required = true)]
+        tags: Vec<String>,
+    },
+
+    /// Remove tags from a task
+    Untag {
+        id: String,
+        #[arg(required = true)]
+        tags: Vec<String>,
+    },
+
+    /// Show a task's notes, or replace them when TEXT is given
+    Notes {
+        id: String,
+        text: Option<String>,
+    },
+
+    /// Make a task a subtask of the task above it
+    Indent {
+        id: String,
+    },
+
+    /// Move a subtask up one level, after its current parent
+    Outdent {
+        id: String,
+    },
+
+    /// Make a task a subtask of another task
+    MoveUnder {
+        id: String,
+        parent: String,
+    },
+
+    /// Reorder a task, or move it next to a task in another list
+    #[command(group(ArgGroup::new("placement").required(true)))]
+    Move {
+        id: String,
+
+        /// Place it just before this task
+        #[arg(long, group = "placement")]
+        before: Option<String>,
+
+        /// Place it just after this task
+        #[arg(long, group = "placement")]
+        after: Option<String>,
+
+        /// Place it first among its siblings
+        #[arg(long, group = "placement")]
+        top: bool,
+
+        /// Place it last among its siblings
+        #[arg(long, group = "placement")]
+        bottom: bool,
     },
 
     /// List all tasks
-    List,
+    List {
+        /// Order the list by this field
+        #[arg(long, value_enum)]
+        sort: Option<SortKey>,
+
+        /// Only show tasks at this priority or above
+        #[arg(long, value_enum)]
+        priority: Option<Priority>,
+
+        /// Only show open tasks past their due date
+        #[arg(long)]
+        overdue: bool,
+
+        /// Only show tasks with this tag; repeat to require several
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+    },
+
+    /// Show task lists, or manage them
+    Lists {
+        #[command(subcommand)]
+        action: Option<ListCommands>,
+    },
+
+    /// Show or change settings in the config file
+    Config {
+        #[command(subcommand)]
+        action: ConfigCommands,
+    },
+
+    /// Manage this device's peer identity
+    Identity {
+        #[command(subcommand)]
+        action: IdentityCommands,
+    },
+
+    /// Manage trusted peers
+    Peers {
+        #[command(subcommand)]
+        action: PeerCommands,
+    },
+}
+
+#[derive(Clone, Debug, Subcommand, Serialize, Deserialize)]
+pub enum ListCommands {
+    /// Create a new, empty list
+    New {
+        name: String,
+    },
+
+    /// Rename a list
+    Rename {
+        list: String,
+        name: String,
+    },
+
+    /// Delete a list from this device
+    Delete {
+        list: String,
+    },
+
+    /// Make a list the one commands act on by default
+    Switch {
+        list: String,
+    },
+
+    /// Choose which lists are synced with a peer
+    Share {
+        peer_id: String,
+
+        /// Lists to share with the peer; all other lists are kept private
+        #[arg(required_unless_present = "all", conflicts_with = "all")]
+        lists: Vec<String>,
+
+        /// Share every list, including ones created later
+        #[arg(long)]
+        all: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommands {
+    /// Print the config file location and every setting
+    Show,
+
+    /// Print one setting
+    Get {
+        key: String,
+    },
+
+    /// Change one setting (for peers, a comma-separated list)
+    Set {
+        key: String,
+        value: String,
+    },
+
+    /// Reset one setting to its default
+    Unset {
+        key: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum IdentityCommands {
+    /// Show the peer id and public key of this device
+    Show,
+
+    /// Replace the current identity with a freshly generated one
+    New {
+        /// Overwrite an existing keyfile
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Write the private key to a file
+    Export {
+        path: PathBuf,
+    },
+
+    /// Replace the current identity with a key previously exported
+    Import {
+        path: PathBuf,
+
+        /// Overwrite an existing keyfile
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum PeerCommands {
+    /// Trust a peer by its base64 public key (see `identity show` on that device)
+    Trust {
+        public_key: String,
+
+        /// A name to remember the peer by
+        #[arg(long)]
+        name: Option<String>,
+    },
+
+    /// Stop trusting a peer, by peer id or a unique prefix of it
+    Revoke {
+        peer_id: String,
+    },
+
+    /// List trusted peers
+    ListTrusted,
+
+    /// Show or set what happens when an unknown peer connects
+    Policy {
+        policy: Option<TrustPolicy>,
+    },
 }
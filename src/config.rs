@@ -0,0 +1,162 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Deserializer, Serialize};
+use crate::trust::TrustPolicy;
+
+const CONFIG_FILE_NAME: &str = "config.toml";
+pub const DEFAULT_PORT: u16 = 58008;
+
+/// Settings read from `config.toml` at startup. Every field has a default,
+/// so the file only needs the ones being changed; command-line flags
+/// override whatever it says.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Addresses the interactive session accepts peers on. A single
+    /// string is accepted too.
+    #[serde(deserialize_with = "one_or_many")]
+    pub listen: Vec<String>,
+    /// Port used when connecting to a peer given without one.
+    pub connect_port: u16,
+    /// Where task lists, identity and trusted peers are kept.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data_dir: Option<PathBuf>,
+    /// Colored output.
+    pub color: bool,
+    /// The RustyTasks banner in interactive mode.
+    pub banner: bool,
+    /// Peers to connect to when interactive mode starts.
+    pub peers: Vec<String>,
+    /// Announce this device on the LAN and look for other peers.
+    pub discovery: bool,
+    /// Overrides the policy stored with the trusted peers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trust_policy: Option<TrustPolicy>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            // Dual-stack: IPv4 and IPv6 on separate sockets.
+            listen: vec![format!("0.0.0.0:{}", DEFAULT_PORT), format!("[::]:{}", DEFAULT_PORT)],
+            connect_port: DEFAULT_PORT,
+            data_dir: None,
+            color: true,
+            banner: true,
+            peers: Vec::new(),
+            discovery: true,
+            trust_policy: None,
+        }
+    }
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+fn invalid(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+impl Config {
+    /// `<config dir>/rustytasks/config.toml`, e.g.
+    /// `~/.config/rustytasks/config.toml` on Linux.
+    pub fn default_path() -> PathBuf {
+        dirs::config_dir()
+            .map(|dir| dir.join("rustytasks"))
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join(CONFIG_FILE_NAME)
+    }
+
+    /// Reads the config at `path`; a missing file means all defaults.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(contents) => toml::from_str(&contents)
+                .map_err(|e| invalid(format!("Invalid config file {}: {}", path.display(), e))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, self.to_toml()?)
+    }
+
+    pub fn to_toml(&self) -> io::Result<String> {
+        toml::to_string(self).map_err(invalid)
+    }
+
+    fn to_table(&self) -> io::Result<toml::Table> {
+        toml::Table::try_from(self).map_err(invalid)
+    }
+
+    /// The value of one setting, formatted as TOML.
+    pub fn get(&self, key: &str) -> io::Result<String> {
+        Self::check_key(key)?;
+        Ok(self.to_table()?.get(key).map(|value| value.to_string()).unwrap_or_default())
+    }
+
+    /// Changes one setting. `raw` is read as a TOML value where possible,
+    /// otherwise as a plain string; `listen` and `peers` also take a
+    /// comma-separated list. The result is checked by reading the whole
+    /// config back.
+    pub fn set(&mut self, key: &str, raw: &str) -> io::Result<()> {
+        Self::check_key(key)?;
+        let raw = raw.trim();
+        let parsed = toml::from_str::<toml::Table>(&format!("value = {}", raw))
+            .ok()
+            .and_then(|mut table| table.remove("value"));
+        let value = match parsed {
+            Some(value @ toml::Value::Array(_)) => value,
+            _ if key == "listen" || key == "peers" => toml::Value::Array(
+                raw.split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(|item| toml::Value::String(item.to_string()))
+                    .collect(),
+            ),
+            Some(value) => value,
+            None => toml::Value::String(raw.to_string()),
+        };
+
+        let mut table = self.to_table()?;
+        table.insert(key.to_string(), value);
+        *self = table.try_into().map_err(|e| invalid(format!("Invalid value for {}: {}", key, e)))?;
+        Ok(())
+    }
+
+    /// Resets one setting to its default.
+    pub fn unset(&mut self, key: &str) -> io::Result<()> {
+        Self::check_key(key)?;
+        let mut table = self.to_table()?;
+        table.remove(key);
+        *self = table.try_into().map_err(invalid)?;
+        Ok(())
+    }
+
+    fn check_key(key: &str) -> io::Result<()> {
+        const KEYS: &[&str] = &["listen", "connect_port", "data_dir", "color", "banner", "peers", "discovery", "trust_policy"];
+        if KEYS.contains(&key) {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown config key '{}'; expected one of {}", key, KEYS.join(", ")),
+            ))
+        }
+    }
+}
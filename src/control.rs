@@ -0,0 +1,297 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use crate::cli::{Commands, ListCommands};
+use crate::crdt::Placement;
+use crate::network::NetworkContext;
+use crate::sync::sync_peers;
+use crate::tasks::{normalize_tag, parse_due, Task};
+use crate::workspace::{ShareScope, Workspace};
+
+const SOCKET_FILE_NAME: &str = "daemon.sock";
+
+/// One of the one-shot commands, in the form it is handed to the daemon.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Request {
+    Add { task: Task, parent: Option<String> },
+    Remove { id: String },
+    Done { id: String },
+    Undone { id: String },
+    Edit { id: String, name: String },
+    SetNotes { id: String, text: String },
+    ShowNotes { id: String },
+    Tag { id: String, tags: Vec<String> },
+    Untag { id: String, tags: Vec<String> },
+    Indent { id: String },
+    Outdent { id: String },
+    MoveUnder { id: String, parent: String },
+    Move { id: String, placement: Placement },
+    List,
+    Lists { action: Option<ListCommands> },
+}
+
+/// A request plus the global flags that affect it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ControlRequest {
+    /// `--list`: the list to act on instead of the current one.
+    pub list: Option<String>,
+    pub auto_complete_parents: bool,
+    pub request: Request,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "reply", rename_all = "snake_case")]
+pub enum Reply {
+    Done,
+    /// Text to print as is.
+    Text { text: String },
+    Tasks { tasks: Vec<Task> },
+    Error { message: String },
+}
+
+impl Request {
+    /// The request a command line stands for, or `None` for commands that
+    /// don't act on tasks or lists.
+    pub fn from_command(command: &Commands) -> Result<Option<Self>, String> {
+        let request = match command {
+            Commands::Add { name, notes, due, priority, tags, parent } => {
+                let mut todo = Vec::new();
+                Task::add_task(&mut todo, name.trim().to_string());
+                let mut task = todo.pop().expect("add_task adds a task");
+                task.notes = notes.clone().unwrap_or_default();
+                task.due = due.as_deref().map(parse_due).transpose()?;
+                task.priority = *priority;
+                task.tags = tags.iter().map(|tag| normalize_tag(tag)).filter(|tag| !tag.is_empty()).collect();
+                Request::Add { task, parent: parent.clone() }
+            }
+            Commands::Remove { id } => Request::Remove { id: id.clone() },
+            Commands::Done { id } => Request::Done { id: id.clone() },
+            Commands::Undone { id } => Request::Undone { id: id.clone() },
+            Commands::Edit { id, name } => Request::Edit { id: id.clone(), name: name.trim().to_string() },
+            Commands::Notes { id, text: Some(text) } => Request::SetNotes { id: id.clone(), text: text.clone() },
+            Commands::Notes { id, text: None } => Request::ShowNotes { id: id.clone() },
+            Commands::Tag { id, tags } => Request::Tag { id: id.clone(), tags: tags.clone() },
+            Commands::Untag { id, tags } => Request::Untag { id: id.clone(), tags: tags.clone() },
+            Commands::Indent { id } => Request::Indent { id: id.clone() },
+            Commands::Outdent { id } => Request::Outdent { id: id.clone() },
+            Commands::MoveUnder { id, parent } => Request::MoveUnder { id: id.clone(), parent: parent.clone() },
+            Commands::Move { id, before, after, top, bottom: _ } => {
+                let placement = match (before, after) {
+                    (Some(target), _) => Placement::Before(target.clone()),
+                    (_, Some(target)) => Placement::After(target.clone()),
+                    _ if *top => Placement::Top,
+                    _ => Placement::Bottom,
+                };
+                Request::Move { id: id.clone(), placement }
+            }
+            Commands::List { .. } => Request::List,
+            Commands::Lists { action } => Request::Lists { action: action.clone() },
+            _ => return Ok(None),
+        };
+        Ok(Some(request))
+    }
+}
+
+/// Carries out `request` on the workspace's current list (or on the
+/// workspace itself, for list commands). The caller saves and syncs.
+pub fn apply(workspace: &mut Workspace, request: &Request) -> Result<Reply, String> {
+    if let Request::Lists { action } = request {
+        return run_lists_command(workspace, action.as_ref()).map(|text| Reply::Text { text });
+    }
+
+    let crdt = workspace.current_mut();
+    let result = match request {
+        Request::Add { task, parent: Some(parent) } => crdt.add_subtask_offline(parent, task),
+        Request::Add { task, parent: None } => crdt.add_task_offline(task).map_err(Into::into),
+        Request::Remove { id } => crdt.remove_task_offline(id),
+        Request::Done { id } => crdt.mark_done_offline(id),
+        Request::Undone { id } => crdt.mark_undone_offline(id),
+        Request::Edit { id, name } => crdt.edit_task_offline(id, name),
+        Request::SetNotes { id, text } => crdt.set_notes_offline(id, text),
+        Request::Tag { id, tags } => crdt.tag_task_offline(id, tags),
+        Request::Untag { id, tags } => crdt.untag_task_offline(id, tags),
+        Request::Indent { id } => crdt.indent_task_offline(id),
+        Request::Outdent { id } => crdt.outdent_task_offline(id),
+        Request::MoveUnder { id, parent } => crdt.move_under_offline(id, parent),
+        Request::Move { id, placement } => crdt.move_task_offline(id, placement),
+        Request::ShowNotes { id } => {
+            return crdt
+                .find_task(id)
+                .map(|entry| Reply::Text { text: format!("{}\n\n{}", entry.task.name, entry.task.notes) })
+                .map_err(|e| e.to_string());
+        }
+        Request::List => {
+            return Ok(Reply::Tasks { tasks: crdt.task_entries.iter().map(|entry| entry.task.clone()).collect() });
+        }
+        Request::Lists { .. } => unreachable!(),
+    };
+    result.map(|()| Reply::Done).map_err(|e| e.to_string())
+}
+
+fn run_lists_command(workspace: &mut Workspace, action: Option<&ListCommands>) -> Result<String, String> {
+    let text = match action {
+        None => workspace
+            .summaries()
+            .iter()
+            .map(|list| {
+                let marker = if list.current { "*" } else { " " };
+                format!("{} {:<20} {:<18} {} tasks", marker, list.name, list.key, list.tasks)
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+
+        Some(ListCommands::New { name }) => {
+            let key = workspace.create(name)?;
+            format!("Created list '{}' ({})", name.trim(), key)
+        }
+
+        Some(ListCommands::Rename { list, name }) => {
+            workspace.rename(list, name)?;
+            format!("Renamed list '{}' to '{}'", list, name.trim())
+        }
+
+        Some(ListCommands::Delete { list }) => {
+            let name = workspace.delete(list)?;
+            format!("Deleted list '{}'", name)
+        }
+
+        Some(ListCommands::Switch { list }) => {
+            workspace.switch(list)?;
+            format!("Switched to list '{}'", workspace.current_name())
+        }
+
+        Some(ListCommands::Share { peer_id, lists, all }) => {
+            if *all {
+                workspace.share(peer_id, ShareScope::All);
+                format!("Sharing all lists with {}", peer_id)
+            } else {
+                let keys = lists.iter().map(|list| workspace.resolve(list)).collect::<Result<_, _>>()?;
+                workspace.share(peer_id, ShareScope::Only(keys));
+                format!("Sharing {} with {}", lists.join(", "), peer_id)
+            }
+        }
+    };
+    Ok(text)
+}
+
+/// The control socket of the daemon keeping `data_dir`.
+pub fn socket_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(SOCKET_FILE_NAME)
+}
+
+/// Hands `request` to the daemon listening on `path`. Returns `None` when no
+/// daemon is running there, so the caller can do the work itself.
+#[cfg(unix)]
+pub async fn send(path: &Path, request: &ControlRequest) -> io::Result<Option<Reply>> {
+    let stream = match tokio::net::UnixStream::connect(path).await {
+        Ok(stream) => stream,
+        Err(e) if matches!(e.kind(), io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let (reader, mut writer) = stream.into_split();
+    let mut line = serde_json::to_vec(request).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+
+    let mut reply = String::new();
+    BufReader::new(reader).read_line(&mut reply).await?;
+    serde_json::from_str(&reply)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("unexpected reply from the daemon: {}", e)))
+}
+
+#[cfg(not(unix))]
+pub async fn send(_path: &Path, _request: &ControlRequest) -> io::Result<Option<Reply>> {
+    Ok(None)
+}
+
+/// Whether a daemon is answering on `path`.
+#[cfg(unix)]
+pub async fn daemon_running(path: &Path) -> bool {
+    tokio::net::UnixStream::connect(path).await.is_ok()
+}
+
+#[cfg(not(unix))]
+pub async fn daemon_running(_path: &Path) -> bool {
+    false
+}
+
+/// Binds the control socket, replacing one left behind by a daemon that
+/// didn't shut down cleanly. Only the owner may connect.
+#[cfg(unix)]
+pub async fn bind(path: &Path) -> io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if daemon_running(path).await {
+        return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("a daemon is already running on {}", path.display())));
+    }
+    match std::fs::remove_file(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Answers requests on the control socket, one JSON line in and one out per
+/// connection. Changes are saved and sent to connected peers before the
+/// reply goes out.
+#[cfg(unix)]
+pub async fn serve(listener: tokio::net::UnixListener, ctx: NetworkContext) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            let (reader, mut writer) = stream.into_split();
+            let mut line = String::new();
+            if BufReader::new(reader).read_line(&mut line).await.is_err() {
+                return;
+            }
+            let reply = match serde_json::from_str::<ControlRequest>(&line) {
+                Ok(request) => handle(&ctx, &request).await,
+                Err(e) => Reply::Error { message: format!("malformed request: {}", e) },
+            };
+            if let Ok(mut out) = serde_json::to_vec(&reply) {
+                out.push(b'\n');
+                let _ = writer.write_all(&out).await;
+            }
+        });
+    }
+}
+
+async fn handle(ctx: &NetworkContext, request: &ControlRequest) -> Reply {
+    let mut workspace = ctx.workspace.lock().await;
+    workspace.set_auto_complete_parents(request.auto_complete_parents);
+    if let Some(list) = &request.list {
+        if let Err(message) = workspace.select(list) {
+            return Reply::Error { message };
+        }
+    }
+
+    let reply = apply(&mut workspace, &request.request).unwrap_or_else(|message| Reply::Error { message });
+    // `--list` only lasts for the one request.
+    workspace.reset_current();
+
+    if !matches!(request.request, Request::List | Request::ShowNotes { .. }) {
+        // Sharing may have changed, too.
+        for peer in ctx.shared_peers.lock().await.values_mut() {
+            peer.shared_lists = workspace.scope_for(&peer.peer_id.id);
+        }
+        for key in workspace.list_keys() {
+            if let Some(crdt) = workspace.get_mut(&key) {
+                sync_peers(crdt, &ctx.shared_peers).await;
+            }
+        }
+        if let Err(e) = workspace.save() {
+            return Reply::Error { message: format!("failed to save: {}", e) };
+        }
+    }
+    reply
+}
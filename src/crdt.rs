@@ -1,51 +1,100 @@
 use std::borrow::Cow;
-use std::fs::File;
-use std::io::{stdin, stdout, Read, Write};
-use automerge::{AutoCommit, AutomergeError, Change, ObjId, ObjType, ReadDoc, ScalarValue, Value, ROOT};
-use automerge::transaction::Transactable;
-use crate::display::show_welcome_screen;
-use crate::network::Message;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use chrono::{Local, TimeZone};
+use automerge::{ActorId, AutoCommit, AutomergeError, ChangeHash, ObjId, ObjType, ReadDoc, ScalarValue, Value, ROOT};
+use automerge::sync::{self, SyncDoc};
+use automerge::transaction::{CommitOptions, Transactable};
+use serde::{Deserialize, Serialize};
 use crate::peer::SharedPeers;
-use crate::sync::SyncState;
-use crate::tasks::Task;
+use crate::position::key_between;
+use crate::storage::{self, LogPosition};
+use crate::sync::sync_peers;
+use crate::tasks::{new_task_id, normalize_tag, Priority, Task, TaskLookupError};
+
+/// Size the change log may reach before it is folded into the snapshot, as
+/// long as it's smaller than the snapshot.
+const COMPACTION_THRESHOLD: u64 = 64 * 1024;
+
+#[derive(Debug)]
+pub enum CrdtError {
+    Automerge(AutomergeError),
+    Lookup(TaskLookupError),
+    InvalidMove(String),
+}
+
+/// Where `move` puts a task among its siblings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Placement {
+    /// Just before the given task ID (or unique prefix).
+    Before(String),
+    /// Just after the given task ID (or unique prefix).
+    After(String),
+    Top,
+    Bottom,
+}
+
+impl fmt::Display for CrdtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CrdtError::Automerge(e) => e.fmt(f),
+            CrdtError::Lookup(e) => e.fmt(f),
+            CrdtError::InvalidMove(reason) => f.write_str(reason),
+        }
+    }
+}
+
+impl std::error::Error for CrdtError {}
+
+impl From<AutomergeError> for CrdtError {
+    fn from(e: AutomergeError) -> Self {
+        CrdtError::Automerge(e)
+    }
+}
+
+impl From<TaskLookupError> for CrdtError {
+    fn from(e: TaskLookupError) -> Self {
+        CrdtError::Lookup(e)
+    }
+}
 
 pub struct CrdtToDoList {
+    /// Identifies this list to peers; each named list is its own document.
+    pub list_key: String,
     doc: AutoCommit,
     list_id: ObjId,
     pub task_entries: Vec<TaskEntry>,
+    /// Mark a task done once all of its subtasks are done.
+    pub auto_complete_parents: bool,
+    /// Heads of the changes already saved, so a save only appends new ones.
+    saved_heads: Vec<ChangeHash>,
+    /// How far the change log on disk has been read.
+    log_position: Option<LogPosition>,
 }
 
 pub struct TaskEntry {
     pub obj_id: ObjId,
+    /// The list holding this task: the top-level list or its parent's `children`.
+    pub list_id: ObjId,
     pub task: Task,
 }
 
 impl CrdtToDoList {
-    pub fn new(path: Option<&str>) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut doc = if let Some(path) = path {
-            match File::open(path) {
-                Ok(mut file) => {
-                    let mut bytes = Vec::new();
-                    file.read_to_end(&mut bytes)?;
-                    AutoCommit::load(&bytes)?
-                },
-                Err(_) => {
-                    AutoCommit::new()
-                }
-            }
-        } else {
-            AutoCommit::new()
-        };
+    pub fn new(list_key: &str, path: Option<&Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut doc = AutoCommit::new();
+        let mut log_position = None;
+        if let Some(path) = path {
+            log_position = load_saved(&mut doc, None, path)?;
+        }
+        let saved_heads = doc.get_heads();
+        if saved_heads.is_empty() {
+            doc = new_document();
+        }
 
-        let list_id = doc
-            .get(ROOT, "tasks")?
-            .and_then(|(val, obj_id)| {
-                if matches!(val, Value::Object(ObjType::List)) {
-                    Some(obj_id)
-                } else {
-                    None
-                }
-            })
+        let list_id = find_task_list(&doc)?
             .unwrap_or_else(|| {
                 // If "tasks" list doesn't exist, create it
                 doc.put_object(ROOT, "tasks", ObjType::List)
@@ -53,9 +102,13 @@ impl CrdtToDoList {
             });
 
         let mut todo_list = CrdtToDoList {
+            list_key: list_key.to_string(),
             doc,
             list_id,
             task_entries: Vec::new(),
+            auto_complete_parents: false,
+            saved_heads,
+            log_position,
         };
 
         todo_list.load_tasks()?;
@@ -65,61 +118,126 @@ impl CrdtToDoList {
 
     pub fn add_task_offline(&mut self, task: &Task) -> Result<(), AutomergeError>{
         println!("Adding task to CRDT: {}", task.name);
-        let index = self.doc.length(&self.list_id);
-        let task_obj = self.doc.insert_object(&self.list_id, index, ObjType::Map)?;
-        self.doc.put(&task_obj, "name", task.name.clone())?;
-        self.doc.put(&task_obj, "status", task.status)?;
-        self.task_entries.push(TaskEntry {
-            obj_id: task_obj,
-            task: Task {
-                name: task.name.clone(),
-                status: task.status,
-            },
-        });
+        let list_id = self.list_id.clone();
+        self.insert_task(&list_id, task)?;
         Ok(())
     }
 
-    pub async fn add_task(&mut self, task: &Task, sync_state: &mut SyncState, shared_peers: &SharedPeers) -> Result<(), AutomergeError>{
+    pub async fn add_task(&mut self, task: &Task, shared_peers: &SharedPeers) -> Result<(), AutomergeError>{
         print!("Adding task to CRDT: {}", task.name);
-        let index = self.doc.length(&self.list_id);
-        let task_obj = self.doc.insert_object(&self.list_id, index, ObjType::Map)?;
-        self.doc.put(&task_obj, "name", task.name.clone())?;
-        self.doc.put(&task_obj, "status", task.status)?;
-        self.task_entries.push(TaskEntry {
-            obj_id: task_obj,
-            task: Task {
-                name: task.name.clone(),
-                status: task.status,
-            },
-        });
-        self.send_changes(sync_state, shared_peers).await;
-        println!("{}", "Press Enter to continue...");
-        let mut input = String::new();
-        let _ = stdout().flush();
-        stdin().read_line(&mut input).expect("Failed to read line");
-        show_welcome_screen();
+        let list_id = self.list_id.clone();
+        self.insert_task(&list_id, task)?;
+        sync_peers(self, shared_peers).await;
         Ok(())
     }
 
+    /// Appends a new task object to `list_id` (the top-level list or a
+    /// task's `children`) and reloads the task entries.
+    fn insert_task(&mut self, list_id: &ObjId, task: &Task) -> Result<ObjId, AutomergeError> {
+        let position = self.position_at_end(list_id)?;
+        let index = self.doc.length(list_id);
+        let task_obj = self.doc.insert_object(list_id, index, ObjType::Map)?;
+        self.doc.put(&task_obj, "id", task.id.clone())?;
+        self.doc.put(&task_obj, "position", position)?;
+        self.write_text(&task_obj, "name", &task.name)?;
+        self.write_text(&task_obj, "notes", &task.notes)?;
+        self.doc.put(&task_obj, "status", task.status)?;
+        self.doc.put(&task_obj, "priority", task.priority.as_str())?;
+        // Tags are keys of a map, so concurrent additions merge and a removal
+        // only deletes the one tag.
+        let tags_obj = self.doc.put_object(&task_obj, "tags", ObjType::Map)?;
+        for tag in &task.tags {
+            self.doc.put(&tags_obj, tag.as_str(), true)?;
+        }
+        if let Some(due) = task.due {
+            self.doc.put(&task_obj, "due", ScalarValue::Timestamp(due.timestamp_millis()))?;
+        }
+        // Created up front so subtasks added concurrently on different peers
+        // end up in the same list.
+        self.doc.put_object(&task_obj, "children", ObjType::List)?;
+        self.load_tasks()?;
+        Ok(task_obj)
+    }
+
     fn load_tasks(&mut self) -> Result<(), AutomergeError> {
-        self.task_entries.clear();
+        loop {
+            self.task_entries.clear();
 
-        let len = self.doc.length(&self.list_id);
+            // A merge can change which object wins the `tasks` key.
+            if let Some(list_id) = find_task_list(&self.doc)? {
+                self.list_id = list_id;
+            }
 
-        for i in 0..len {
-            let (_, obj_id) = self.doc.get(&self.list_id, i)?.ok_or(AutomergeError::InvalidIndex(i))?;
+            let list_id = self.list_id.clone();
+            self.load_list(&list_id, 0, None)?;
+            if !self.remove_duplicate()? && !self.rescue_orphan()? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Two peers moving the same task to another list at once each copy it
+    /// there (see `place`), leaving two tasks with one id. Keeps the copy
+    /// with the lowest object id, which every peer picks alike, and deletes
+    /// the others along with their subtasks. Handles the first duplicated id
+    /// in tree order, so copied subtasks are only looked at once their
+    /// parent is settled. Returns whether anything was deleted.
+    fn remove_duplicate(&mut self) -> Result<bool, AutomergeError> {
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for entry in &self.task_entries {
+            *counts.entry(entry.task.id.as_str()).or_default() += 1;
+        }
+        let Some(duplicate) = self.task_entries.iter().find(|e| counts[e.task.id.as_str()] > 1) else {
+            return Ok(false);
+        };
+
+        let copies: Vec<(ObjId, ObjId)> = self
+            .task_entries
+            .iter()
+            .filter(|e| e.task.id == duplicate.task.id)
+            .map(|e| (e.obj_id.clone(), e.list_id.clone()))
+            .collect();
+        let keep = copies.iter().map(|(obj_id, _)| obj_id).min().cloned();
+        let mut removed = false;
+        for (obj_id, list_id) in copies {
+            if Some(&obj_id) == keep.as_ref() {
+                continue;
+            }
+            if let Some(index) = self.list_index_of(&list_id, &obj_id) {
+                self.doc.delete(&list_id, index)?;
+                removed = true;
+            }
+        }
+        Ok(removed)
+    }
 
-            let name_val = self.doc.get(&obj_id, "name")?.ok_or(AutomergeError::InvalidIndex(i))?;
+    /// Loads the tasks of one list, depth first, so every task is followed
+    /// by its subtasks.
+    fn load_list(&mut self, list_id: &ObjId, depth: usize, parent: Option<&str>) -> Result<(), AutomergeError> {
+        for (i, (obj_id, _)) in self.ordered_children(list_id)?.into_iter().enumerate() {
 
             let status_val = self.doc.get(&obj_id, "status")?.ok_or(AutomergeError::InvalidIndex(i))?;
 
-            let name_str = match name_val.0 {
-                Value::Scalar(Cow::Borrowed(ScalarValue::Str(s))) => s.to_string(),
-                Value::Scalar(Cow::Owned(ScalarValue::Str(s))) => s.to_string(),
-                _ => {
-                    eprintln!("Unexpected format for task name at index {}", i);
-                    continue;
-                }
+            let Some(name_str) = self.read_text(&obj_id, "name")? else {
+                eprintln!("Unexpected format for task name at index {}", i);
+                continue;
+            };
+            let notes = self.read_text(&obj_id, "notes")?.unwrap_or_default();
+
+            let due = match self.doc.get(&obj_id, "due")? {
+                Some((Value::Scalar(v), _)) => match v.as_ref() {
+                    ScalarValue::Timestamp(ms) => Local.timestamp_millis_opt(*ms).single(),
+                    _ => None,
+                },
+                _ => None,
+            };
+
+            let priority = match self.doc.get(&obj_id, "priority")? {
+                Some((Value::Scalar(v), _)) => match v.as_ref() {
+                    ScalarValue::Str(s) => Priority::parse(s).unwrap_or_default(),
+                    _ => Priority::default(),
+                },
+                _ => Priority::default(),
             };
 
             let status_bool = match status_val.0 {
@@ -131,135 +249,886 @@ impl CrdtToDoList {
                 }
             };
 
+            // Tasks created before ids existed get one the first time they are seen.
+            let id = match self.stored_id(&obj_id)? {
+                Some(id) => id,
+                None => self.assign_id(&obj_id)?,
+            };
+
+            let tags = match self.doc.get(&obj_id, "tags")? {
+                Some((Value::Object(ObjType::Map), tags_obj)) => self.doc.keys(&tags_obj).collect(),
+                _ => BTreeSet::new(),
+            };
+
             let task = Task {
+                id,
                 name: name_str,
+                notes,
                 status: status_bool,
+                due,
+                priority,
+                tags,
+                depth,
+                parent: parent.map(str::to_string),
             };
 
-            self.task_entries.push(TaskEntry { obj_id, task });
+            let task_id = task.id.clone();
+            self.task_entries.push(TaskEntry { obj_id: obj_id.clone(), list_id: list_id.clone(), task });
+
+            if let Some((Value::Object(ObjType::List), children)) = self.doc.get(&obj_id, "children")? {
+                self.load_list(&children, depth + 1, Some(&task_id))?;
+            }
         }
 
         Ok(())
     }
 
-    pub fn remove_task_offline(&mut self, index:usize) -> Result<(), AutomergeError>{
-        println!("Removing the task from CRDT");
-        self.doc.delete(&self.list_id, index)?;
+    /// Moves across lists on two peers at once can leave the only copy of
+    /// a task inside a deleted task: moving T under P on one peer and P
+    /// under T on another deletes both originals, each holding the other's
+    /// copy. Every copy `place` makes is recorded in the `moved` map, so such
+    /// a copy can be found again; the outermost one is copied back to the
+    /// end of the top-level list, with its subtasks. Returns whether a task
+    /// was brought back.
+    fn rescue_orphan(&mut self) -> Result<bool, AutomergeError> {
+        let visible: HashSet<&str> = self.task_entries.iter().map(|e| e.task.id.as_str()).collect();
+        let mut orphans: Vec<(String, ObjId)> = Vec::new();
+        for registry in self.moved_registries()? {
+            for id in self.doc.keys(&registry) {
+                if visible.contains(id.as_str()) || orphans.iter().any(|(orphan, _)| *orphan == id) {
+                    continue;
+                }
+                let Some((Value::Scalar(v), _)) = self.doc.get(&registry, id.as_str())? else { continue };
+                let ScalarValue::Str(copy) = v.as_ref() else { continue };
+                let Ok(copy) = self.doc.import_obj(copy) else { continue };
+                if self.stored_id(&copy).ok().flatten().as_deref() != Some(id.as_str()) {
+                    continue;
+                }
+                // Only a copy that is itself still in place, under a deleted task.
+                let mut parents = self.doc.parents(&copy)?;
+                if parents.next().is_some_and(|parent| parent.visible) {
+                    orphans.push((id, copy));
+                }
+            }
+        }
+        orphans.sort();
+
+        let mut outermost = None;
+        for (_, copy) in &orphans {
+            let path = self.doc.parents(copy)?.path();
+            if !path.iter().any(|(obj, _)| orphans.iter().any(|(_, other)| other == obj)) {
+                outermost = Some(copy.clone());
+                break;
+            }
+        }
+        let Some(orphan) = outermost else { return Ok(false) };
+
+        let list_id = self.list_id.clone();
+        let position = self.position_at_end(&list_id)?;
+        let copy = self.doc.insert_object(&list_id, self.doc.length(&list_id), ObjType::Map)?;
+        self.copy_contents(&orphan, &copy)?;
+        self.doc.put(&copy, "position", position)?;
+        self.record_moves(&copy)?;
+        Ok(true)
+    }
+
+    /// Every `moved` map in the document; two peers creating it at once
+    /// leave two.
+    fn moved_registries(&self) -> Result<Vec<ObjId>, AutomergeError> {
+        Ok(self
+            .doc
+            .get_all(ROOT, "moved")?
+            .into_iter()
+            .filter(|(value, _)| matches!(value, Value::Object(ObjType::Map)))
+            .map(|(_, obj_id)| obj_id)
+            .collect())
+    }
+
+    /// Points the `moved` entries of the task `task_obj` and its subtasks at
+    /// these objects.
+    fn record_moves(&mut self, task_obj: &ObjId) -> Result<(), AutomergeError> {
+        let registry = match self.doc.get(ROOT, "moved")? {
+            Some((Value::Object(ObjType::Map), registry)) => registry,
+            _ => self.doc.put_object(ROOT, "moved", ObjType::Map)?,
+        };
+        let mut pending = vec![task_obj.clone()];
+        while let Some(obj_id) = pending.pop() {
+            if let Some(id) = self.stored_id(&obj_id)? {
+                self.doc.put(&registry, id.as_str(), obj_id.to_string())?;
+            }
+            if let Some((Value::Object(ObjType::List), children)) = self.doc.get(&obj_id, "children")? {
+                for i in 0..self.doc.length(&children) {
+                    if let Some((_, child)) = self.doc.get(&children, i)? {
+                        pending.push(child);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn stored_id(&self, obj_id: &ObjId) -> Result<Option<String>, AutomergeError> {
+        match self.doc.get(obj_id, "id")? {
+            Some((Value::Scalar(v), _)) => match v.as_ref() {
+                ScalarValue::Str(s) => Ok(Some(s.to_string())),
+                _ => Ok(None),
+            },
+            _ => Ok(None),
+        }
+    }
+
+    fn position_of(&self, obj_id: &ObjId) -> Result<Option<String>, AutomergeError> {
+        match self.doc.get(obj_id, "position")? {
+            Some((Value::Scalar(v), _)) => match v.as_ref() {
+                ScalarValue::Str(s) => Ok(Some(s.to_string())),
+                _ => Ok(None),
+            },
+            _ => Ok(None),
+        }
+    }
+
+    /// The tasks of a list in display order, with their ordering keys.
+    ///
+    /// Tasks are ordered by their `position` key rather than by where they
+    /// sit in the Automerge list, so a move only rewrites one key and two
+    /// peers reordering at once still agree on the result. Equal keys (from
+    /// concurrent moves into the same gap) fall back to the object id, which
+    /// is the same on every peer. Tasks from before ordering keys existed
+    /// have none and keep their list order, ahead of the keyed ones.
+    fn ordered_children(&self, list_id: &ObjId) -> Result<Vec<(ObjId, Option<String>)>, AutomergeError> {
+        let mut children = Vec::new();
+        for i in 0..self.doc.length(list_id) {
+            let (_, obj_id) = self.doc.get(list_id, i)?.ok_or(AutomergeError::InvalidIndex(i))?;
+            let position = self.position_of(&obj_id)?;
+            children.push((obj_id, position));
+        }
+        children.sort_by(|(a_id, a_pos), (b_id, b_pos)| match (a_pos, b_pos) {
+            (Some(a), Some(b)) => a.cmp(b).then_with(|| a_id.cmp(b_id)),
+            _ => a_pos.cmp(b_pos),
+        });
+        Ok(children)
+    }
+
+    /// Like `ordered_children`, but first gives any task without an ordering
+    /// key one that keeps it where it is.
+    fn keyed_children(&mut self, list_id: &ObjId) -> Result<Vec<(ObjId, String)>, AutomergeError> {
+        let children = self.ordered_children(list_id)?;
+        let first_key = children.iter().find_map(|(_, position)| position.clone());
+
+        let mut keyed = Vec::with_capacity(children.len());
+        let mut previous: Option<String> = None;
+        for (obj_id, position) in children {
+            let position = match position {
+                Some(position) => position,
+                None => {
+                    let position = key_between(previous.as_deref(), first_key.as_deref());
+                    self.doc.put(&obj_id, "position", position.as_str())?;
+                    position
+                }
+            };
+            previous = Some(position.clone());
+            keyed.push((obj_id, position));
+        }
+        Ok(keyed)
+    }
+
+    fn position_at_end(&mut self, list_id: &ObjId) -> Result<String, AutomergeError> {
+        let keyed = self.keyed_children(list_id)?;
+        Ok(key_between(keyed.last().map(|(_, key)| key.as_str()), None))
+    }
+
+    /// Reads a text field. Older documents stored names as plain strings,
+    /// so those are accepted too.
+    fn read_text(&self, obj_id: &ObjId, key: &str) -> Result<Option<String>, AutomergeError> {
+        match self.doc.get(obj_id, key)? {
+            Some((Value::Object(ObjType::Text), text_id)) => Ok(Some(self.doc.text(&text_id)?)),
+            Some((Value::Scalar(Cow::Borrowed(ScalarValue::Str(s))), _)) => Ok(Some(s.to_string())),
+            Some((Value::Scalar(Cow::Owned(ScalarValue::Str(s))), _)) => Ok(Some(s.to_string())),
+            _ => Ok(None),
+        }
+    }
+
+    /// Sets a text field to `value`. An existing `Text` object is updated
+    /// with character-level splices, so concurrent edits to different parts
+    /// of the text from several peers are all kept after merging. A missing
+    /// field, or a plain string from an older document, is replaced by a
+    /// new `Text` object.
+    fn write_text(&mut self, obj_id: &ObjId, key: &str, value: &str) -> Result<(), AutomergeError> {
+        match self.doc.get(obj_id, key)? {
+            Some((Value::Object(ObjType::Text), text_id)) => self.doc.update_text(&text_id, value),
+            _ => {
+                let text_id = self.doc.put_object(obj_id, key, ObjType::Text)?;
+                self.doc.splice_text(&text_id, 0, 0, value)
+            }
+        }
+    }
+
+    fn assign_id(&mut self, obj_id: &ObjId) -> Result<String, AutomergeError> {
+        let id = new_task_id();
+        self.doc.put(obj_id, "id", id.clone())?;
+        Ok(id)
+    }
+
+    /// Looks a task up by its id or a unique prefix of it.
+    pub fn find_task(&self, query: &str) -> Result<&TaskEntry, TaskLookupError> {
+        let index = Task::resolve(self.task_entries.iter().map(|e| &e.task), query)?;
+        Ok(&self.task_entries[index])
+    }
+
+    /// Current position of a task object in `list_id`. Positions shift with
+    /// concurrent edits, so this is only valid until the next merge.
+    fn list_index_of(&self, list_id: &ObjId, obj_id: &ObjId) -> Option<usize> {
+        (0..self.doc.length(list_id)).find(|&i| {
+            matches!(self.doc.get(list_id, i), Ok(Some((_, ref id))) if id == obj_id)
+        })
+    }
+
+    /// Removes a task, along with its subtasks. Their `moved` entries go
+    /// too, so `rescue_orphan` doesn't bring them back.
+    fn delete_task(&mut self, query: &str) -> Result<(), CrdtError> {
+        let index = Task::resolve(self.task_entries.iter().map(|e| &e.task), query)?;
+        let entry = &self.task_entries[index];
+        let (obj_id, list_id) = (entry.obj_id.clone(), entry.list_id.clone());
+        let removed: Vec<String> = std::iter::once(entry)
+            .chain(self.task_entries[index + 1..].iter().take_while(|e| e.task.depth > entry.task.depth))
+            .map(|e| e.task.id.clone())
+            .collect();
+        if let Some(index) = self.list_index_of(&list_id, &obj_id) {
+            self.doc.delete(&list_id, index)?;
+        }
+        for registry in self.moved_registries()? {
+            for id in &removed {
+                if self.doc.get(&registry, id.as_str())?.is_some() {
+                    self.doc.delete(&registry, id.as_str())?;
+                }
+            }
+        }
         self.load_tasks()?;
         Ok(())
     }
 
-    pub async fn remove_task(&mut self, index:usize, sync_state: &mut SyncState, shared_peers: &SharedPeers) -> Result<(), AutomergeError>{
+    /// The `children` list of a task, created if the task predates subtasks.
+    fn children_list(&mut self, task_obj: &ObjId) -> Result<ObjId, AutomergeError> {
+        match self.doc.get(task_obj, "children")? {
+            Some((Value::Object(ObjType::List), children)) => Ok(children),
+            _ => self.doc.put_object(task_obj, "children", ObjType::List),
+        }
+    }
+
+    fn insert_subtask(&mut self, parent_query: &str, task: &Task) -> Result<(), CrdtError> {
+        let parent_obj = self.find_task(parent_query)?.obj_id.clone();
+        let children = self.children_list(&parent_obj)?;
+        self.insert_task(&children, task)?;
+        Ok(())
+    }
+
+    pub fn add_subtask_offline(&mut self, parent_query: &str, task: &Task) -> Result<(), CrdtError> {
+        println!("Adding subtask to CRDT: {}", task.name);
+        self.insert_subtask(parent_query, task)
+    }
+
+    pub async fn add_subtask(&mut self, parent_query: &str, task: &Task, shared_peers: &SharedPeers) -> Result<(), CrdtError> {
+        println!("Adding subtask to CRDT: {}", task.name);
+        self.insert_subtask(parent_query, task)?;
+        sync_peers(self, shared_peers).await;
+        Ok(())
+    }
+
+    /// Copies everything inside `src` into the empty object `dst` of the
+    /// same type, recursing into nested maps, lists and text.
+    fn copy_contents(&mut self, src: &ObjId, dst: &ObjId) -> Result<(), AutomergeError> {
+        match self.doc.object_type(src)? {
+            ObjType::Map | ObjType::Table => {
+                let keys: Vec<String> = self.doc.keys(src).collect();
+                for key in keys {
+                    match self.doc.get(src, key.as_str())? {
+                        Some((Value::Object(obj_type), child)) => {
+                            let copy = self.doc.put_object(dst, key.as_str(), obj_type)?;
+                            self.copy_contents(&child, &copy)?;
+                        }
+                        Some((Value::Scalar(value), _)) => self.doc.put(dst, key.as_str(), value.into_owned())?,
+                        None => {}
+                    }
+                }
+            }
+            ObjType::List => {
+                for i in 0..self.doc.length(src) {
+                    match self.doc.get(src, i)? {
+                        Some((Value::Object(obj_type), child)) => {
+                            let copy = self.doc.insert_object(dst, i, obj_type)?;
+                            self.copy_contents(&child, &copy)?;
+                        }
+                        Some((Value::Scalar(value), _)) => self.doc.insert(dst, i, value.into_owned())?,
+                        None => {}
+                    }
+                }
+            }
+            ObjType::Text => {
+                let text = self.doc.text(src)?;
+                self.doc.splice_text(dst, 0, 0, &text)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Gives a task (with its subtasks) the ordering key `position` in
+    /// `dest_list`. Within the same list only the key changes. Automerge has
+    /// no move operation across lists, so there the task is copied and the
+    /// original deleted; edits a peer makes to the old copy while the move is
+    /// in flight are lost, copies made by concurrent moves are merged back
+    /// into one by `remove_duplicate`, and copies left inside a deleted task
+    /// are recovered by `rescue_orphan`.
+    fn place(&mut self, obj_id: &ObjId, list_id: &ObjId, dest_list: &ObjId, position: String) -> Result<(), AutomergeError> {
+        if dest_list == list_id {
+            self.doc.put(obj_id, "position", position)?;
+        } else {
+            let index = self.doc.length(dest_list);
+            let copy = self.doc.insert_object(dest_list, index, ObjType::Map)?;
+            self.copy_contents(obj_id, &copy)?;
+            self.doc.put(&copy, "position", position)?;
+            if let Some(old_index) = self.list_index_of(list_id, obj_id) {
+                self.doc.delete(list_id, old_index)?;
+            }
+            self.record_moves(&copy)?;
+        }
+        self.load_tasks()
+    }
+
+    /// Errors if `target_query` is the task `task_id` or one of its subtasks.
+    fn check_not_within(&self, task_id: &str, target_query: &str) -> Result<(), CrdtError> {
+        let mut ancestor = Some(self.find_task(target_query)?);
+        while let Some(current) = ancestor {
+            if current.task.id == task_id {
+                return Err(CrdtError::InvalidMove("A task can't be moved under itself or its subtasks".to_string()));
+            }
+            ancestor = current.task.parent.as_deref().and_then(|id| self.find_task(id).ok());
+        }
+        Ok(())
+    }
+
+    /// Makes a task the last subtask of the task just above it.
+    fn indent(&mut self, query: &str) -> Result<(), CrdtError> {
+        let entry = self.find_task(query)?;
+        let (obj_id, list_id) = (entry.obj_id.clone(), entry.list_id.clone());
+        let siblings = self.ordered_children(&list_id)?;
+        let index = siblings.iter().position(|(id, _)| *id == obj_id).unwrap_or(0);
+        let Some((sibling, _)) = index.checked_sub(1).map(|i| siblings[i].clone()) else {
+            return Err(CrdtError::InvalidMove("There is no task above it to indent under".to_string()));
+        };
+        let children = self.children_list(&sibling)?;
+        let position = self.position_at_end(&children)?;
+        self.place(&obj_id, &list_id, &children, position)?;
+        Ok(())
+    }
+
+    pub fn indent_task_offline(&mut self, query: &str) -> Result<(), CrdtError> {
+        println!("Indenting the task");
+        self.indent(query)
+    }
+
+    pub async fn indent_task(&mut self, query: &str, shared_peers: &SharedPeers) -> Result<(), CrdtError> {
+        println!("Indenting the task");
+        self.indent(query)?;
+        sync_peers(self, shared_peers).await;
+        Ok(())
+    }
+
+    /// Moves a subtask up one level, right after its current parent.
+    fn outdent(&mut self, query: &str) -> Result<(), CrdtError> {
+        let entry = self.find_task(query)?;
+        let (obj_id, list_id) = (entry.obj_id.clone(), entry.list_id.clone());
+        let Some(parent_id) = entry.task.parent.clone() else {
+            return Err(CrdtError::InvalidMove("The task is already at the top level".to_string()));
+        };
+        let parent = self.find_task(&parent_id)?;
+        let (parent_obj, parent_list) = (parent.obj_id.clone(), parent.list_id.clone());
+        let position = self.position_after(&parent_list, &parent_obj)?;
+        self.place(&obj_id, &list_id, &parent_list, position)?;
+        Ok(())
+    }
+
+    pub fn outdent_task_offline(&mut self, query: &str) -> Result<(), CrdtError> {
+        println!("Outdenting the task");
+        self.outdent(query)
+    }
+
+    pub async fn outdent_task(&mut self, query: &str, shared_peers: &SharedPeers) -> Result<(), CrdtError> {
+        println!("Outdenting the task");
+        self.outdent(query)?;
+        sync_peers(self, shared_peers).await;
+        Ok(())
+    }
+
+    /// Makes a task the last subtask of `parent_query`.
+    fn move_under(&mut self, query: &str, parent_query: &str) -> Result<(), CrdtError> {
+        let entry = self.find_task(query)?;
+        let (obj_id, list_id, task_id) = (entry.obj_id.clone(), entry.list_id.clone(), entry.task.id.clone());
+        self.check_not_within(&task_id, parent_query)?;
+
+        let parent_obj = self.find_task(parent_query)?.obj_id.clone();
+        let children = self.children_list(&parent_obj)?;
+        let position = self.position_at_end(&children)?;
+        self.place(&obj_id, &list_id, &children, position)?;
+        Ok(())
+    }
+
+    /// A key for a task placed right after `after_obj` in `list_id`.
+    fn position_after(&mut self, list_id: &ObjId, after_obj: &ObjId) -> Result<String, AutomergeError> {
+        let keyed = self.keyed_children(list_id)?;
+        let index = keyed.iter().position(|(id, _)| id == after_obj);
+        let before = index.map(|i| keyed[i].1.as_str());
+        let after = index.and_then(|i| keyed.get(i + 1)).map(|(_, key)| key.as_str());
+        Ok(key_between(before, after))
+    }
+
+    /// Moves a task among its siblings, or next to a task in another list.
+    /// The latter copies the task like `indent` does, so concurrent moves
+    /// are reconciled the same way.
+    fn reorder(&mut self, query: &str, placement: &Placement) -> Result<(), CrdtError> {
+        let entry = self.find_task(query)?;
+        let (obj_id, list_id, task_id) = (entry.obj_id.clone(), entry.list_id.clone(), entry.task.id.clone());
+
+        let (dest_list, target) = match placement {
+            Placement::Before(target) | Placement::After(target) => {
+                self.check_not_within(&task_id, target)?;
+                let target = self.find_task(target)?;
+                (target.list_id.clone(), Some(target.obj_id.clone()))
+            }
+            Placement::Top | Placement::Bottom => (list_id.clone(), None),
+        };
+
+        let siblings: Vec<(ObjId, String)> = self
+            .keyed_children(&dest_list)?
+            .into_iter()
+            .filter(|(id, _)| *id != obj_id)
+            .collect();
+        let key = |i: usize| siblings.get(i).map(|(_, key)| key.as_str());
+        let target_index = target.and_then(|target| siblings.iter().position(|(id, _)| *id == target));
+
+        let position = match (placement, target_index) {
+            (Placement::Top, _) => key_between(None, key(0)),
+            (Placement::Bottom, _) => key_between(siblings.last().map(|(_, key)| key.as_str()), None),
+            (Placement::Before(_), Some(i)) => key_between(i.checked_sub(1).and_then(key), key(i)),
+            (Placement::After(_), Some(i)) => key_between(key(i), key(i + 1)),
+            (_, None) => return Err(CrdtError::InvalidMove("The target task was not found".to_string())),
+        };
+        self.place(&obj_id, &list_id, &dest_list, position)?;
+        Ok(())
+    }
+
+    pub fn move_task_offline(&mut self, query: &str, placement: &Placement) -> Result<(), CrdtError> {
+        println!("Moving the task");
+        self.reorder(query, placement)
+    }
+
+    pub async fn move_task(&mut self, query: &str, placement: &Placement, shared_peers: &SharedPeers) -> Result<(), CrdtError> {
+        println!("Moving the task");
+        self.reorder(query, placement)?;
+        sync_peers(self, shared_peers).await;
+        Ok(())
+    }
+
+    pub fn move_under_offline(&mut self, query: &str, parent_query: &str) -> Result<(), CrdtError> {
+        println!("Moving the task");
+        self.move_under(query, parent_query)
+    }
+
+    pub async fn move_task_under(&mut self, query: &str, parent_query: &str, shared_peers: &SharedPeers) -> Result<(), CrdtError> {
+        println!("Moving the task");
+        self.move_under(query, parent_query)?;
+        sync_peers(self, shared_peers).await;
+        Ok(())
+    }
+
+    pub fn remove_task_offline(&mut self, query: &str) -> Result<(), CrdtError>{
         println!("Removing the task from CRDT");
-        self.doc.delete(&self.list_id, index)?;
+        self.delete_task(query)
+    }
+
+    pub async fn remove_task(&mut self, query: &str, shared_peers: &SharedPeers) -> Result<(), CrdtError>{
+        println!("Removing the task from CRDT");
+        self.delete_task(query)?;
+        sync_peers(self, shared_peers).await;
+        Ok(())
+    }
+
+    fn set_status(&mut self, query: &str, status: bool) -> Result<(), CrdtError> {
+        let entry = self.find_task(query)?;
+        let (obj_id, mut parent) = (entry.obj_id.clone(), entry.task.parent.clone());
+        self.doc.put(&obj_id, "status", status)?;
         self.load_tasks()?;
-        self.send_changes(sync_state, shared_peers).await;
-        println!("{}", "Press Enter to continue...");
-        let mut input = String::new();
-        let _ = stdout().flush();
-        stdin().read_line(&mut input).expect("Failed to read line");
-        show_welcome_screen();
+
+        // Completing the last open subtask completes the parent, and so on up.
+        while status && self.auto_complete_parents {
+            let Some(parent_id) = parent else { break };
+            let all_done = self
+                .task_entries
+                .iter()
+                .filter(|e| e.task.parent.as_deref() == Some(parent_id.as_str()))
+                .all(|e| e.task.status);
+            let parent_entry = self.find_task(&parent_id)?;
+            if !all_done || parent_entry.task.status {
+                break;
+            }
+            let parent_obj = parent_entry.obj_id.clone();
+            parent = parent_entry.task.parent.clone();
+            self.doc.put(&parent_obj, "status", true)?;
+            self.load_tasks()?;
+        }
         Ok(())
     }
 
-    pub fn mark_done_offline(&mut self, index: usize) -> Result<(), AutomergeError> {
+    pub fn mark_done_offline(&mut self, query: &str) -> Result<(), CrdtError> {
+        println!("Marking the task done");
+        self.set_status(query, true)
+    }
+
+    pub async fn mark_done(&mut self, query: &str, shared_peers: &SharedPeers) -> Result<(), CrdtError> {
         println!("Marking the task done");
-        if index >= self.task_entries.len() {
-            println!("Invalid index: {}", index);
-            return Ok(());
+        self.set_status(query, true)?;
+        sync_peers(self, shared_peers).await;
+        Ok(())
+    }
+
+    pub fn mark_undone_offline(&mut self, query: &str) -> Result<(), CrdtError> {
+        println!("Reopening the task");
+        self.set_status(query, false)
+    }
+
+    pub async fn mark_undone(&mut self, query: &str, shared_peers: &SharedPeers) -> Result<(), CrdtError> {
+        println!("Reopening the task");
+        self.set_status(query, false)?;
+        sync_peers(self, shared_peers).await;
+        Ok(())
+    }
+
+    fn rename_task(&mut self, query: &str, name: &str) -> Result<(), CrdtError> {
+        let task_id = self.find_task(query)?.obj_id.clone();
+        self.write_text(&task_id, "name", name)?;
+        self.load_tasks()?;
+        Ok(())
+    }
+
+    pub fn edit_task_offline(&mut self, query: &str, name: &str) -> Result<(), CrdtError> {
+        println!("Renaming the task to: {}", name);
+        self.rename_task(query, name)
+    }
+
+    pub async fn edit_task(&mut self, query: &str, name: &str, shared_peers: &SharedPeers) -> Result<(), CrdtError> {
+        println!("Renaming the task to: {}", name);
+        self.rename_task(query, name)?;
+        sync_peers(self, shared_peers).await;
+        Ok(())
+    }
+
+    fn tags_object(&mut self, task_id: &ObjId) -> Result<ObjId, AutomergeError> {
+        match self.doc.get(task_id, "tags")? {
+            Some((Value::Object(ObjType::Map), tags_obj)) => Ok(tags_obj),
+            _ => self.doc.put_object(task_id, "tags", ObjType::Map),
+        }
+    }
+
+    pub fn tag_task_offline(&mut self, query: &str, tags: &[String]) -> Result<(), CrdtError> {
+        let task_id = self.find_task(query)?.obj_id.clone();
+        let tags_obj = self.tags_object(&task_id)?;
+        for tag in tags.iter().map(|tag| normalize_tag(tag)).filter(|tag| !tag.is_empty()) {
+            println!("Tagging the task with #{}", tag);
+            self.doc.put(&tags_obj, tag.as_str(), true)?;
         }
-        let task_id = &self.task_entries[index].obj_id;
-        self.doc.put(task_id, "status", true)?;
         self.load_tasks()?;
-        println!("{}", "Press Enter to continue...");
-        let mut input = String::new();
-        let _ = stdout().flush();
-        stdin().read_line(&mut input).expect("Failed to read line");
-        show_welcome_screen();
         Ok(())
     }
 
-    pub async fn mark_done(&mut self, index: usize, sync_state: &mut SyncState, shared_peers: &SharedPeers) -> Result<(), AutomergeError> {
-        println!("Marking the task done");
-        if index >= self.task_entries.len() {
-            println!("Invalid index: {}", index);
-            return Ok(());
+    pub fn untag_task_offline(&mut self, query: &str, tags: &[String]) -> Result<(), CrdtError> {
+        let task_id = self.find_task(query)?.obj_id.clone();
+        let tags_obj = self.tags_object(&task_id)?;
+        for tag in tags.iter().map(|tag| normalize_tag(tag)) {
+            if self.doc.get(&tags_obj, tag.as_str())?.is_some() {
+                println!("Removing #{} from the task", tag);
+                self.doc.delete(&tags_obj, tag.as_str())?;
+            }
         }
-        let task_id = &self.task_entries[index].obj_id;
-        self.doc.put(task_id, "status", true)?;
-        self.send_changes(sync_state, shared_peers).await;
         self.load_tasks()?;
-        println!("{}", "Press Enter to continue...");
-        let mut input = String::new();
-        let _ = stdout().flush();
-        stdin().read_line(&mut input).expect("Failed to read line");
-        show_welcome_screen();
         Ok(())
     }
 
-    pub fn save_to_file(&mut self, path: &str) -> std::io::Result<()> {
-        let mut file = File::create(path)?;
-        let bytes = self.doc.save();
-        file.write_all(&bytes)?;
+    fn write_notes(&mut self, query: &str, notes: &str) -> Result<(), CrdtError> {
+        let task_id = self.find_task(query)?.obj_id.clone();
+        self.write_text(&task_id, "notes", notes)?;
+        self.load_tasks()?;
         Ok(())
     }
 
-    pub async fn send_changes(
-        &mut self,
-        sync_state: &mut SyncState,
-        shared_peers: &SharedPeers,
-    ) {
-        let have_deps = sync_state.get_have_deps();
-    
-        let changes = self.doc.get_changes(&have_deps);
-        let owned_changes: Vec<Change> = changes.iter().map(|c| (*c).to_owned()).collect();
-        let raw_changes: Vec<Vec<u8>> = owned_changes.iter().map(|c| c.raw_bytes().to_vec()).collect();
-    
-        if raw_changes.is_empty() {
-            return;
-        }
-    
-        let message = Message::Changes(raw_changes);
-    
-        let peers = shared_peers.lock().await;
-        for (peer_id, peer) in peers.iter() {
-            if let Some(sender) = &peer.sender {
-                if let Err(e) = sender.send(message.clone()).await {
-                    eprintln!("Failed to send changes to {}: {}", peer_id.id, e);
-                }
-            } else {
-                eprintln!("No sender channel found for peer: {}", peer_id.id);
-            }
+    pub fn set_notes_offline(&mut self, query: &str, notes: &str) -> Result<(), CrdtError> {
+        println!("Updating the task notes");
+        self.write_notes(query, notes)
+    }
+
+    pub async fn set_notes(&mut self, query: &str, notes: &str, shared_peers: &SharedPeers) -> Result<(), CrdtError> {
+        println!("Updating the task notes");
+        self.write_notes(query, notes)?;
+        sync_peers(self, shared_peers).await;
+        Ok(())
+    }
+
+    /// Identifies the document's current state; changes whenever a change
+    /// is made or received.
+    pub fn heads(&mut self) -> Vec<automerge::ChangeHash> {
+        self.doc.get_heads()
+    }
+
+    /// The list's display name, shared with peers. Renames made on two
+    /// peers at once resolve to one of them.
+    pub fn name(&self) -> Option<String> {
+        match self.doc.get(ROOT, "name") {
+            Ok(Some((Value::Scalar(v), _))) => match v.as_ref() {
+                ScalarValue::Str(s) => Some(s.to_string()),
+                _ => None,
+            },
+            _ => None,
         }
-    
-        for change in owned_changes {
-            sync_state.add_received_change(change.hash());
-        }
-    }    
-
-    pub async fn apply_changes_from_bytes(
-        &mut self,
-        raw_changes: Vec<Vec<u8>>,
-        sync_state: &mut SyncState
-    ) {
-        for bytes in raw_changes {
-            match Change::from_bytes(bytes) {
-                Ok(change) => {
-                    if let Err(e) = self.doc.apply_changes(vec![change.clone()]) {
-                        eprintln!("Failed to apply change: {}", e);
-                        continue;
-                    }
-                    sync_state.add_received_change(change.hash());
-                }
-                Err(e) => {
-                    eprintln!("Failed to decode change bytes: {}", e);
-                }
+    }
+
+    pub fn set_name(&mut self, name: &str) -> Result<(), AutomergeError> {
+        self.doc.put(ROOT, "name", name)
+    }
+
+    /// Saves the document to `path` by appending the changes since the last
+    /// save to its change log, which is folded into the snapshot at `path`
+    /// once it grows. Changes another process saved there since are merged
+    /// in first (and show up in `task_entries`), so concurrent invocations
+    /// don't overwrite each other.
+    pub fn save_to_file(&mut self, path: &Path) -> io::Result<()> {
+        let _lock = storage::lock(path)?;
+        let changes = self.doc.save_after(&self.saved_heads);
+        let heads = self.doc.get_heads();
+        let mut position = load_saved(&mut self.doc, self.log_position, path)?;
+        if self.doc.get_heads() != heads {
+            self.load_tasks().map_err(|e| io::Error::other(e.to_string()))?;
+        }
+
+        let log_path = storage::log_path(path);
+        if !changes.is_empty() {
+            position = Some(storage::append_record(&log_path, position, &changes)?);
+        }
+        if let Some(LogPosition { offset, .. }) = position {
+            let snapshot_size = fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0);
+            if offset > COMPACTION_THRESHOLD.max(snapshot_size) {
+                storage::write_atomic(path, &self.doc.save())?;
+                position = Some(storage::new_log(&log_path)?);
             }
         }
+        self.log_position = position;
+        self.saved_heads = self.doc.get_heads();
+        Ok(())
+    }
+
+    /// Produces the next Automerge sync message for the peer whose protocol
+    /// state is `state`, or `None` if that peer is already up to date.
+    pub fn generate_sync_message(&mut self, state: &mut sync::State) -> Option<Vec<u8>> {
+        self.doc.sync().generate_sync_message(state).map(|msg| msg.encode())
+    }
+
+    /// Applies a sync message received from the peer whose protocol state is
+    /// `state`, then reloads the task list. Returns whether it carried any
+    /// changes we didn't already have.
+    pub fn receive_sync_message(&mut self, state: &mut sync::State, bytes: &[u8]) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let message = sync::Message::decode(bytes)?;
+        let heads = self.doc.get_heads();
+        self.doc.sync().receive_sync_message(state, message)?;
+        if self.doc.get_heads() == heads {
+            return Ok(false);
+        }
+        self.load_tasks()?;
+        Ok(true)
+    }
+}
+
+/// Creates an empty task document. Every replica starts from this identical
+/// change (fixed actor and timestamp), so the `tasks` list has the same
+/// object id everywhere and concurrent first edits land in one list instead
+/// of two competing ones.
+fn new_document() -> AutoCommit {
+    let mut doc = AutoCommit::new().with_actor(ActorId::from(&[0u8; 16][..]));
+    doc.put_object(ROOT, "tasks", ObjType::List)
+        .expect("Failed to create task list");
+    doc.commit_with(CommitOptions::default().with_time(0));
+    doc.set_actor(ActorId::random());
+    doc
+}
+
+/// Loads what was saved at `path` since `position` into `doc`: the records
+/// added to the change log since, or the snapshot and the whole log if the
+/// log was compacted in the meantime. An incomplete record at the end of the
+/// log, left by a crash, is skipped. Returns how far the log was read.
+fn load_saved(doc: &mut AutoCommit, position: Option<LogPosition>, path: &Path) -> io::Result<Option<LogPosition>> {
+    let invalid = |e: AutomergeError| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e));
+    // The log is read before the snapshot: a compaction in between leaves
+    // everything the old log held in the new snapshot.
+    let log = storage::read_log(&storage::log_path(path), position)?;
+    let same_log = matches!((&log, position), (Some(log), Some(position)) if log.generation == position.generation);
+    if !same_log {
+        if let Some(snapshot) = storage::read_if_exists(path)? {
+            doc.load_incremental(&snapshot).map_err(invalid)?;
+        }
+    }
+    let Some(log) = log else { return Ok(None) };
+    for record in &log.records {
+        doc.load_incremental(record).map_err(invalid)?;
+    }
+    Ok(Some(log.position()))
+}
+
+fn find_task_list(doc: &AutoCommit) -> Result<Option<ObjId>, AutomergeError> {
+    Ok(doc.get(ROOT, "tasks")?.and_then(|(val, obj_id)| {
+        if matches!(val, Value::Object(ObjType::List)) {
+            Some(obj_id)
+        } else {
+            None
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(name: &str) -> Task {
+        let mut todo = Vec::new();
+        Task::add_task(&mut todo, name.to_string());
+        todo.remove(0)
+    }
+
+    /// A replica of `list` under a different actor, as another peer would
+    /// hold it after syncing.
+    fn fork(list: &mut CrdtToDoList) -> CrdtToDoList {
+        let mut copy = CrdtToDoList {
+            list_key: list.list_key.clone(),
+            doc: list.doc.fork(),
+            list_id: list.list_id.clone(),
+            task_entries: Vec::new(),
+            auto_complete_parents: false,
+            saved_heads: Vec::new(),
+            log_position: None,
+        };
+        copy.load_tasks().unwrap();
+        copy
+    }
+
+    fn merge(into: &mut CrdtToDoList, from: &mut CrdtToDoList) {
+        into.doc.merge(&mut from.doc).unwrap();
+        into.load_tasks().unwrap();
+    }
+
+    fn ids(list: &CrdtToDoList) -> Vec<(String, Option<String>)> {
+        list.task_entries.iter().map(|e| (e.task.id.clone(), e.task.parent.clone())).collect()
+    }
+
+    #[test]
+    fn concurrent_indents_keep_one_copy() {
+        let mut a = CrdtToDoList::new("default", None).unwrap();
+        let (parent, child, grandchild) = (task("parent"), task("child"), task("grandchild"));
+        a.add_task_offline(&parent).unwrap();
+        a.add_task_offline(&child).unwrap();
+        a.add_subtask_offline(&child.id, &grandchild).unwrap();
+        let mut b = fork(&mut a);
+
+        a.indent(&child.id).unwrap();
+        b.indent(&child.id).unwrap();
+        merge(&mut a, &mut b);
+        merge(&mut b, &mut a);
+
+        let expected = vec![
+            (parent.id.clone(), None),
+            (child.id.clone(), Some(parent.id.clone())),
+            (grandchild.id.clone(), Some(child.id.clone())),
+        ];
+        assert_eq!(ids(&a), expected);
+        assert_eq!(ids(&b), expected);
+        assert_eq!(a.find_task(&child.id).unwrap().obj_id, b.find_task(&child.id).unwrap().obj_id);
+    }
+
+    #[test]
+    fn moving_two_tasks_under_each_other_keeps_both() {
+        let mut a = CrdtToDoList::new("default", None).unwrap();
+        let (first, second) = (task("first"), task("second"));
+        let (first_sub, second_sub) = (task("first sub"), task("second sub"));
+        a.add_task_offline(&first).unwrap();
+        a.add_task_offline(&second).unwrap();
+        a.add_subtask_offline(&first.id, &first_sub).unwrap();
+        a.add_subtask_offline(&second.id, &second_sub).unwrap();
+        let mut b = fork(&mut a);
+
+        a.move_under(&first.id, &second.id).unwrap();
+        b.move_under(&second.id, &first.id).unwrap();
+        merge(&mut a, &mut b);
+        merge(&mut b, &mut a);
+        merge(&mut a, &mut b);
+
+        assert_eq!(ids(&a), ids(&b));
+        for (id, parent) in [(&first.id, None), (&second.id, None), (&first_sub.id, Some(&first.id)), (&second_sub.id, Some(&second.id))] {
+            let entries: Vec<&TaskEntry> = a.task_entries.iter().filter(|e| e.task.id == *id).collect();
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].task.parent.as_ref(), parent);
+        }
+    }
+
+    #[test]
+    fn removed_task_that_was_moved_stays_removed() {
+        let mut a = CrdtToDoList::new("default", None).unwrap();
+        let (parent, child, grandchild) = (task("parent"), task("child"), task("grandchild"));
+        a.add_task_offline(&parent).unwrap();
+        a.add_task_offline(&child).unwrap();
+        a.add_subtask_offline(&child.id, &grandchild).unwrap();
+        a.indent(&child.id).unwrap();
 
-        if let Err(e) = self.load_tasks() {
-            eprintln!("Failed to reload task entries: {}", e);
+        a.delete_task(&parent.id).unwrap();
+        assert!(a.task_entries.is_empty());
+    }
+
+    #[test]
+    fn concurrent_moves_into_other_lists_keep_one_copy() {
+        let mut a = CrdtToDoList::new("default", None).unwrap();
+        let (first, second, moved) = (task("first"), task("second"), task("moved"));
+        let (first_sub, second_sub) = (task("first sub"), task("second sub"));
+        for parent in [&first, &second, &moved] {
+            a.add_task_offline(parent).unwrap();
         }
+        a.add_subtask_offline(&first.id, &first_sub).unwrap();
+        a.add_subtask_offline(&second.id, &second_sub).unwrap();
+        let mut b = fork(&mut a);
+
+        a.reorder(&moved.id, &Placement::After(first_sub.id.clone())).unwrap();
+        b.reorder(&moved.id, &Placement::Before(second_sub.id.clone())).unwrap();
+        merge(&mut a, &mut b);
+        merge(&mut b, &mut a);
+
+        assert_eq!(ids(&a), ids(&b));
+        assert_eq!(a.task_entries.iter().filter(|e| e.task.id == moved.id).count(), 1);
+        assert_eq!(a.find_task(&moved.id).unwrap().obj_id, b.find_task(&moved.id).unwrap().obj_id);
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn compaction_by_another_process_is_read_in_full() {
+        let dir = storage::ScratchDir::new("compaction");
+        let path = dir.join("default.automerge");
+        let (first, second, third) = (task("first"), task("second"), task("third"));
+
+        let mut a = CrdtToDoList::new("default", Some(&path)).unwrap();
+        a.add_task_offline(&first).unwrap();
+        a.save_to_file(&path).unwrap();
+
+        // Another process adds a task, compacts, and appends to the new log.
+        let mut b = CrdtToDoList::new("default", Some(&path)).unwrap();
+        b.add_task_offline(&second).unwrap();
+        b.save_to_file(&path).unwrap();
+        storage::write_atomic(&path, &b.doc.save()).unwrap();
+        b.log_position = Some(storage::new_log(&storage::log_path(&path)).unwrap());
+        b.add_task_offline(&third).unwrap();
+        b.save_to_file(&path).unwrap();
+
+        a.save_to_file(&path).unwrap();
+        let names: Vec<&str> = a.task_entries.iter().map(|e| e.task.name.as_str()).collect();
+        assert_eq!(names, ["first", "second", "third"]);
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::sync::Mutex;
+use crate::network::NetworkContext;
+use crate::peer::PeerId;
+use crate::reconnect::{maintain, LinkState};
+
+/// Administratively scoped multicast group, so announcements stay on the LAN.
+const DISCOVERY_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 58, 8);
+const DISCOVERY_PORT: u16 = 58009;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5);
+/// Peers not heard from for this long drop off the nearby list.
+const NEARBY_TIMEOUT: Duration = Duration::from_secs(30);
+const APP_TAG: &str = "rustytasks";
+
+/// What every instance multicasts periodically. Nothing here is trusted:
+/// the peer id is only a hint, and the handshake still proves who is on
+/// the other end.
+#[derive(Debug, Serialize, Deserialize)]
+struct Announcement {
+    app: String,
+    peer_id: String,
+    port: u16,
+}
+
+#[derive(Clone, Debug)]
+pub struct NearbyPeer {
+    pub address: SocketAddr,
+    pub first_seen: Instant,
+    pub last_seen: Instant,
+}
+
+pub type NearbyPeers = Arc<Mutex<HashMap<String, NearbyPeer>>>;
+
+/// Peers heard from recently, dropping the ones that went quiet.
+pub async fn nearby_peers(nearby: &NearbyPeers) -> Vec<(String, NearbyPeer)> {
+    let mut nearby = nearby.lock().await;
+    nearby.retain(|_, peer| peer.last_seen.elapsed() < NEARBY_TIMEOUT);
+    let mut peers: Vec<_> = nearby.iter().map(|(id, peer)| (id.clone(), peer.clone())).collect();
+    peers.sort_by(|a, b| a.0.cmp(&b.0));
+    peers
+}
+
+/// A socket on the discovery port that receives the group's traffic. Several
+/// instances on one host can share the port.
+fn discovery_socket() -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT).into())?;
+    socket.join_multicast_v4(&DISCOVERY_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    // Let instances on the same machine see each other.
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Announces this instance on the LAN every few seconds, records the peers
+/// it hears in `nearby`, and connects to the trusted ones that aren't
+/// connected yet. `port` is the TCP port peers should connect to.
+pub async fn run_discovery(ctx: NetworkContext, nearby: NearbyPeers, port: u16) -> std::io::Result<()> {
+    let socket = Arc::new(discovery_socket()?);
+    let our_id = ctx.identity.derive_peer_id().id;
+
+    let announcement = serde_json::to_vec(&Announcement { app: APP_TAG.to_string(), peer_id: our_id.clone(), port })
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    let sender = socket.clone();
+    tokio::spawn(async move {
+        let group = SocketAddr::from((DISCOVERY_GROUP, DISCOVERY_PORT));
+        let mut interval = tokio::time::interval(ANNOUNCE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = sender.send_to(&announcement, group).await {
+                eprintln!("Failed to announce on the LAN: {}", e);
+            }
+        }
+    });
+
+    let mut buf = [0u8; 512];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        let Ok(announcement) = serde_json::from_slice::<Announcement>(&buf[..len]) else { continue };
+        if announcement.app != APP_TAG || announcement.peer_id == our_id {
+            continue;
+        }
+
+        let address = SocketAddr::new(from.ip(), announcement.port);
+        let now = Instant::now();
+        let first_seen = {
+            let mut nearby = nearby.lock().await;
+            let entry = nearby
+                .entry(announcement.peer_id.clone())
+                .or_insert(NearbyPeer { address, first_seen: now, last_seen: now });
+            entry.address = address;
+            entry.last_seen = now;
+            entry.first_seen
+        };
+
+        // When both sides trust each other, the one with the lower id dials
+        // and the other only steps in if that hasn't happened after a while,
+        // so they don't open two sessions at once.
+        let waited = now.duration_since(first_seen) >= ANNOUNCE_INTERVAL * 2;
+        if announcement.peer_id > our_id && !waited {
+            continue;
+        }
+        let trusted = {
+            let mut trust = ctx.trust.lock().await;
+            trust.refresh();
+            trust.peers.contains_key(&announcement.peer_id)
+        };
+        if !trusted {
+            continue;
+        }
+        if ctx.shared_peers.lock().await.contains_key(&PeerId { id: announcement.peer_id.clone() }) {
+            continue;
+        }
+        // Leave addresses that are already being retried to their backoff.
+        if ctx.links.lock().await.get(&address.to_string()).is_some_and(|state| !matches!(state, LinkState::GaveUp(_))) {
+            continue;
+        }
+
+        println!("\nConnecting to nearby trusted peer '{}' at {}", announcement.peer_id, address);
+        tokio::spawn(maintain(address.to_string(), ctx.clone()));
+    }
+}
@@ -1,8 +1,23 @@
 use figlet_rs::FIGfont;
 use colored::*;
 use std::io::{stdin, stdout, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static BANNER: AtomicBool = AtomicBool::new(true);
+
+/// Turns the RustyTasks banner on or off for the rest of the run.
+pub fn set_banner(enabled: bool) {
+    BANNER.store(enabled, Ordering::Relaxed);
+}
+
+fn banner_enabled() -> bool {
+    BANNER.load(Ordering::Relaxed)
+}
 
 pub fn show_welcome_screen_start() {
+    if !banner_enabled() {
+        return;
+    }
     let standard_font = FIGfont::standard().unwrap();
     let figure = standard_font.convert("RustyTasks").unwrap();
 
@@ -19,6 +34,9 @@ pub fn show_welcome_screen_start() {
 }
 
 pub fn show_welcome_screen() {
+    if !banner_enabled() {
+        return;
+    }
     let standard_font = FIGfont::standard().unwrap();
     let figure = standard_font.convert("RustyTasks").unwrap();
 
@@ -30,6 +48,10 @@ pub fn show_welcome_screen() {
 }
 
 pub fn show_welcome_screen_exit() {
+    if !banner_enabled() {
+        println!("{}", "Thank you for using the to-do list!".blue().bold());
+        return;
+    }
     let standard_font = FIGfont::standard().unwrap();
     let figure = standard_font.convert("RustyTasks").unwrap();
 
@@ -39,4 +61,12 @@ pub fn show_welcome_screen_exit() {
 
     println!("{}\n\n", "A CRDT-powered, P2P terminal task manager".italic().dimmed());
     println!("\n{}", "Thank you for using the to-do list!".blue().bold());
-}
\ No newline at end of file
+}
+
+pub fn press_enter_to_continue() {
+    println!("Press Enter to continue...");
+    let mut input = String::new();
+    let _ = stdout().flush();
+    stdin().read_line(&mut input).expect("Failed to read line");
+    show_welcome_screen();
+}
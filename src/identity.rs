@@ -2,9 +2,14 @@ use rand::rngs::OsRng;
 use sha2::{Digest, Sha256};
 use base64::engine::general_purpose;
 use base64::Engine;
-use ed25519_dalek::{SigningKey, PUBLIC_KEY_LENGTH, SECRET_KEY_LENGTH};
+use ed25519_dalek::{Signature, Signer, SigningKey, PUBLIC_KEY_LENGTH, SECRET_KEY_LENGTH};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use crate::peer::PeerId;
 
+const KEYFILE_NAME: &str = "identity.key";
+
 #[derive(Debug)]
 pub struct Identity {
     pub public_key: [u8; PUBLIC_KEY_LENGTH],
@@ -14,17 +19,98 @@ pub struct Identity {
 impl Identity {
     pub fn generate() -> Self {
         let signing_key = SigningKey::generate(&mut OsRng);
+        Self::from_signing_key(&signing_key)
+    }
+
+    pub fn from_signing_key(signing_key: &SigningKey) -> Self {
         let public_key = signing_key.verifying_key().to_bytes();
         let private_key = signing_key.to_bytes();
         Identity { public_key, private_key }
     }
 
+    pub fn signing_key(&self) -> SigningKey {
+        SigningKey::from_bytes(&self.private_key)
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Signature {
+        self.signing_key().sign(message)
+    }
+
     pub fn derive_peer_id(&self) -> PeerId {
-        let mut hasher = Sha256::new();
-        hasher.update(&self.public_key);
-        let hash = hasher.finalize();
-        let short_hash = &hash[..16];
-        let encoded = general_purpose::URL_SAFE_NO_PAD.encode(short_hash);
-        PeerId { id: format!("peer_{}", encoded) }
-    }
-}
\ No newline at end of file
+        peer_id_from_public_key(&self.public_key)
+    }
+
+    /// Location of the keyfile within the data directory.
+    pub fn path_in(data_dir: &Path) -> PathBuf {
+        data_dir.join(KEYFILE_NAME)
+    }
+
+    /// Loads the identity stored at `path`, creating and saving a new one if
+    /// the keyfile does not exist yet.
+    pub fn load_or_generate(path: &Path) -> io::Result<Self> {
+        match Self::load(path) {
+            Ok(identity) => Ok(identity),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let identity = Self::generate();
+                identity.save(path)?;
+                Ok(identity)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        Self::decode(&contents)
+    }
+
+    /// Writes the private key to `path` as base64. On Unix the file is only
+    /// readable and writable by its owner.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(path)?;
+
+        // `mode` only applies when the file is created, so tighten an existing one too.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        }
+
+        writeln!(file, "{}", self.encode())?;
+        file.sync_all()
+    }
+
+    pub fn encode(&self) -> String {
+        general_purpose::STANDARD.encode(self.private_key)
+    }
+
+    pub fn decode(contents: &str) -> io::Result<Self> {
+        let bytes = general_purpose::STANDARD
+            .decode(contents.trim())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid keyfile: {}", e)))?;
+        let private_key: [u8; SECRET_KEY_LENGTH] = bytes.try_into().map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "Invalid keyfile: wrong key length")
+        })?;
+        Ok(Self::from_signing_key(&SigningKey::from_bytes(&private_key)))
+    }
+}
+
+pub fn peer_id_from_public_key(public_key: &[u8; PUBLIC_KEY_LENGTH]) -> PeerId {
+    let mut hasher = Sha256::new();
+    hasher.update(public_key);
+    let hash = hasher.finalize();
+    let short_hash = &hash[..16];
+    let encoded = general_purpose::URL_SAFE_NO_PAD.encode(short_hash);
+    PeerId { id: format!("peer_{}", encoded) }
+}
@@ -6,98 +6,372 @@ mod peer;
 mod identity;
 mod sync;
 mod display;
+mod trust;
+mod transport;
+mod position;
+mod workspace;
+mod config;
+mod discovery;
+mod reconnect;
+mod wire;
+mod control;
+mod storage;
 
 use std::collections::HashMap;
 use clap::Parser;
-use cli::{Cli, Commands};
-use tasks::Task;
-use crdt::CrdtToDoList;
-use sync::SyncState;
+use cli::{Cli, Commands, ConfigCommands, IdentityCommands, PeerCommands};
+use config::{Config, DEFAULT_PORT};
+use control::{ControlRequest, Reply, Request};
+use discovery::{nearby_peers, run_discovery, NearbyPeers};
+use tasks::{normalize_tag, parse_due, ListOptions, Priority, Task};
+use crdt::Placement;
 use std::io::{stdin, stdout, Write};
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
 use identity::Identity;
-use network::{connect_to_peer, connections};
+use network::{connections, split_host_port, NetworkContext};
 use peer::SharedPeers;
+use reconnect::{maintain, KnownPeers, LinkState};
+use trust::{decode_public_key, fingerprint, PairingRequests, TrustPolicy, TrustStore};
+use workspace::{default_data_dir, default_list_file, Workspace};
 use crate::tasks::update_local_list_from_crdt;
-use display::{show_welcome_screen, show_welcome_screen_exit, show_welcome_screen_start};
+use display::{press_enter_to_continue, set_banner, show_welcome_screen, show_welcome_screen_exit, show_welcome_screen_start};
+use base64::engine::general_purpose;
+use base64::Engine;
 
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
+    let config_path = cli.config.clone().unwrap_or_else(Config::default_path);
 
-    // Wrap crdt in Arc<Mutex<>> immediately
-    let crdt_arc = Arc::new(Mutex::new(
-        CrdtToDoList::new(Some("autocommit_doc.automerge")).unwrap_or_else(|e| {
-            eprintln!("Failed to initialize CRDT document: {e}");
+    if let Some(Commands::Config { action }) = &cli.command {
+        if let Err(e) = run_config_command(action, &config_path) {
+            eprintln!("{}", e);
             std::process::exit(1);
-        }),
-    ));
+        }
+        return;
+    }
+
+    let config = load_config(&cli, &config_path);
+    if !config.color {
+        colored::control::set_override(false);
+    }
+    set_banner(config.banner);
+    let data_dir = config.data_dir.clone().unwrap_or_else(default_data_dir);
+
+    if let Some(Commands::Identity { action }) = &cli.command {
+        if let Err(e) = run_identity_command(action, &data_dir) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Commands::Peers { action }) = &cli.command {
+        if let Err(e) = run_peers_command(action, &data_dir, &config) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let request = match cli.command.as_ref().map(Request::from_command).transpose() {
+        Ok(request) => request.flatten(),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let socket = control::socket_path(&data_dir);
+
+    if let (Some(request), Some(command)) = (request, &cli.command) {
+        let request = ControlRequest { list: cli.list.clone(), auto_complete_parents: cli.auto_complete_parents, request };
+        // A running daemon owns the lists, unless another document was asked for.
+        let from_daemon = match cli.file {
+            Some(_) => Ok(None),
+            None => control::send(&socket, &request).await,
+        };
+        let reply = match from_daemon {
+            Ok(Some(reply)) => reply,
+            Ok(None) => {
+                let mut workspace = open_workspace(&cli, &data_dir);
+                let reply = control::apply(&mut workspace, &request.request).unwrap_or_else(|message| Reply::Error { message });
+                workspace.save().unwrap();
+                reply
+            }
+            Err(e) => {
+                eprintln!("Failed to reach the daemon: {}", e);
+                std::process::exit(1);
+            }
+        };
+        print_reply(reply, command);
+        return;
+    }
 
-    let mut todo: Vec<Task> = crdt_arc.lock().await.task_entries.iter().map(|e| e.task.clone()).collect();
+    if control::daemon_running(&socket).await {
+        eprintln!("A daemon is running on {}; stop it first.", socket.display());
+        std::process::exit(1);
+    }
+
+    // Wrap the workspace in Arc<Mutex<>> immediately
+    let workspace = Arc::new(Mutex::new(open_workspace(&cli, &data_dir)));
 
     match &cli.command {
-        Some(Commands::Interactive) | None => {
+        Some(Commands::Relay) => run_relay(workspace.clone(), &data_dir, &config).await,
+        Some(Commands::Daemon) => run_daemon(workspace.clone(), &data_dir, &config).await,
+        _ => {
+            let mut todo: Vec<Task> = workspace.lock().await.current().task_entries.iter().map(|e| e.task.clone()).collect();
             show_welcome_screen_start();
-            run_interactive(&mut todo, crdt_arc.clone()).await;
+            run_interactive(&mut todo, workspace.clone(), &data_dir, &config).await;
         }
+    }
 
-        Some(Commands::Add { name }) => {
-            Task::add_task(&mut todo, name.trim().to_string());
-            if let Some(task) = todo.last() {
-                crdt_arc.lock().await.add_task_offline(task).unwrap_or_else(|e| {
-                    println!("An error \"{}\" has occurred!", e);
-                });
-            }
+    workspace.lock().await.save().unwrap();
+}
+
+/// Opens the lists in `data_dir`, applying `--file`, `--list` and
+/// `--auto-complete-parents`.
+fn open_workspace(cli: &Cli, data_dir: &Path) -> Workspace {
+    let list_file = cli.file.clone().unwrap_or_else(|| default_list_file(data_dir));
+    warn_about_local_document(&list_file);
+    let mut workspace = Workspace::open(data_dir, &list_file).unwrap_or_else(|e| {
+        eprintln!("Failed to initialize CRDT document: {e}");
+        std::process::exit(1);
+    });
+    workspace.set_auto_complete_parents(cli.auto_complete_parents);
+    if let Some(list) = &cli.list {
+        if let Err(e) = workspace.select(list) {
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
+    }
+    workspace
+}
 
-        Some(Commands::Remove { index }) => {
-            Task::remove_task(&mut todo, *index);
-            crdt_arc.lock().await.remove_task_offline(*index).unwrap_or_else(|e| {
-                println!("An error \"{}\" has occurred!", e);
-            });
+/// Prints the outcome of a one-shot command, whether the daemon or this
+/// process carried it out.
+fn print_reply(reply: Reply, command: &Commands) {
+    match reply {
+        Reply::Done => {}
+        Reply::Text { text } => println!("{}", text),
+        Reply::Tasks { tasks } => {
+            let options = match command {
+                Commands::List { sort, priority, overdue, tags } => ListOptions {
+                    sort: *sort,
+                    min_priority: *priority,
+                    overdue_only: *overdue,
+                    tags: tags.clone(),
+                },
+                _ => ListOptions::default(),
+            };
+            Task::list_tasks(&tasks, &options);
+        }
+        Reply::Error { message } if matches!(command, Commands::Lists { .. }) => {
+            eprintln!("{}", message);
+            std::process::exit(1);
         }
+        Reply::Error { message } => println!("An error \"{}\" has occurred!", message),
+    }
+}
 
-        Some(Commands::Done { index }) => {
-            Task::mark_done(&mut todo, *index);
-            crdt_arc.lock().await.mark_done_offline(*index).unwrap_or_else(|e| {
-                println!("An error \"{}\" has occurred!", e);
-            });
+/// Loads this device's identity, trust store and known peers, then starts
+/// accepting peers, LAN discovery (if enabled) and connections to the
+/// configured and previously reached peers.
+async fn start_network(workspace: Arc<Mutex<Workspace>>, data_dir: &Path, config: &Config) -> (NetworkContext, NearbyPeers) {
+    let identity = Arc::new(Identity::load_or_generate(&Identity::path_in(data_dir)).unwrap_or_else(|e| {
+        eprintln!("Failed to load peer identity: {e}");
+        std::process::exit(1);
+    }));
+    let mut trust_store = TrustStore::load(&TrustStore::path_in(data_dir)).unwrap_or_else(|e| {
+        eprintln!("Failed to load trusted peers: {e}");
+        std::process::exit(1);
+    });
+    trust_store.policy_override = config.trust_policy;
+    let known_peers = KnownPeers::load(&KnownPeers::path_in(data_dir)).unwrap_or_else(|e| {
+        eprintln!("Failed to load known peers: {e}");
+        KnownPeers::default()
+    });
+    // Reconnect to the configured peers and to everyone we reached last time.
+    let mut targets = config.peers.clone();
+    for address in known_peers.peers.values() {
+        if !targets.contains(address) {
+            targets.push(address.clone());
         }
+    }
 
-        Some(Commands::List) => {
-            Task::list_tasks(&todo);
+    let shared_peers: SharedPeers = Arc::new(Mutex::new(HashMap::new()));
+    let pairing: PairingRequests = Arc::new(Mutex::new(Vec::new()));
+    let ctx = NetworkContext {
+        identity,
+        shared_peers,
+        workspace,
+        trust: Arc::new(Mutex::new(trust_store)),
+        pairing,
+        listen_addrs: config.listen.clone(),
+        connect_port: config.connect_port,
+        known_peers: Arc::new(Mutex::new(known_peers)),
+        links: Arc::new(Mutex::new(Default::default())),
+    };
+    let ctx_for_listener = ctx.clone();
+    tokio::spawn(async move {
+        if let Err(e) = connections(ctx_for_listener).await {
+            println!("No peers are available: {}!", e);
         }
+    });
+
+    let nearby: NearbyPeers = Arc::new(Mutex::new(HashMap::new()));
+    if config.discovery {
+        // Advertise the port of the first listen address.
+        let port = config
+            .listen
+            .first()
+            .and_then(|addr| split_host_port(addr, DEFAULT_PORT).ok())
+            .map_or(DEFAULT_PORT, |(_, port)| port);
+        let (ctx, nearby) = (ctx.clone(), nearby.clone());
+        tokio::spawn(async move {
+            if let Err(e) = run_discovery(ctx, nearby, port).await {
+                println!("LAN discovery is unavailable: {}", e);
+            }
+        });
     }
 
-    crdt_arc.lock().await.save_to_file("autocommit_doc.automerge").unwrap();
+    for target in targets {
+        tokio::spawn(maintain(target, ctx.clone()));
+    }
+    (ctx, nearby)
 }
 
-async fn run_interactive(todo: &mut Vec<Task>, crdt: Arc<Mutex<CrdtToDoList>>) {
-    let crdt_for_network = crdt.clone();
+/// How often a relay or daemon writes received changes to disk.
+const HEADLESS_SAVE_INTERVAL: Duration = Duration::from_secs(10);
 
-    let shared_peers: SharedPeers = Arc::new(Mutex::new(HashMap::new()));
-    let peers_for_network = shared_peers.clone();
+/// Headless mode for an always-on box: accepts and dials peers like the
+/// interactive mode, keeps every list it receives and hands the changes on
+/// to whoever connects later.
+async fn run_relay(workspace: Arc<Mutex<Workspace>>, data_dir: &Path, config: &Config) {
+    start_headless(workspace.clone(), data_dir, config, "Relaying").await;
+    save_until_shutdown(&workspace).await;
+    println!("Shutting down relay");
+}
 
-    let sync_state = Arc::new(Mutex::new(SyncState::new()));
-    let sync_state_peers = sync_state.clone();
+/// Like the relay, plus a control socket the one-shot commands use while it
+/// runs, so their changes reach peers straight away.
+#[cfg(unix)]
+async fn run_daemon(workspace: Arc<Mutex<Workspace>>, data_dir: &Path, config: &Config) {
+    let socket = control::socket_path(data_dir);
+    let listener = control::bind(&socket).await.unwrap_or_else(|e| {
+        eprintln!("Can't start the daemon: {}", e);
+        std::process::exit(1);
+    });
+    let ctx = start_headless(workspace.clone(), data_dir, config, "Daemon running").await;
     tokio::spawn(async move {
-        if let Err(e) = connections(peers_for_network, crdt_for_network, sync_state_peers).await {
-            println!("No peers are available: {}!", e);
+        if let Err(e) = control::serve(listener, ctx).await {
+            eprintln!("Control socket closed: {}", e);
         }
     });
-    let identity = Identity::generate();
-    let peer_id = identity.derive_peer_id();
-    let public_key = identity.public_key;
-    // let private_key = identity.private_key;
+    println!("Listening for commands on {}", socket.display());
+
+    save_until_shutdown(&workspace).await;
+    let _ = std::fs::remove_file(&socket);
+    println!("Shutting down daemon");
+}
+
+#[cfg(not(unix))]
+async fn run_daemon(_workspace: Arc<Mutex<Workspace>>, _data_dir: &Path, _config: &Config) {
+    eprintln!("The daemon needs Unix domain sockets, which this platform doesn't have.");
+    std::process::exit(1);
+}
+
+/// Starts networking for the relay and the daemon. Nobody is around to
+/// confirm pairing, so the `prompt` policy acts as `reject` and peers have
+/// to be trusted up front.
+async fn start_headless(workspace: Arc<Mutex<Workspace>>, data_dir: &Path, config: &Config, role: &str) -> NetworkContext {
+    let (ctx, _nearby) = start_network(workspace, data_dir, config).await;
+    {
+        let mut trust = ctx.trust.lock().await;
+        if trust.effective_policy() == TrustPolicy::Prompt {
+            trust.policy_override = Some(TrustPolicy::Reject);
+            println!("Unknown peers are rejected; add them with `rustytasks peers trust <PUBKEY>` (no restart needed).");
+        }
+    }
+    println!("{} as '{}' (fingerprint {}) from {}", role, ctx.identity.derive_peer_id().id, fingerprint(&ctx.identity.public_key), data_dir.display());
+    ctx
+}
+
+/// Saves changes received from peers every few seconds until asked to stop.
+async fn save_until_shutdown(workspace: &Mutex<Workspace>) {
+    let mut saved = workspace.lock().await.heads();
+    let mut interval = tokio::time::interval(HEADLESS_SAVE_INTERVAL);
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let mut workspace = workspace.lock().await;
+                let heads = workspace.heads();
+                if heads != saved {
+                    match workspace.save() {
+                        Ok(()) => saved = heads,
+                        Err(e) => eprintln!("Failed to save received changes: {}", e),
+                    }
+                }
+            }
+            _ = &mut shutdown => break,
+        }
+    }
+}
+
+/// Resolves on Ctrl-C, or on SIGTERM where there is one, as sent by service
+/// managers.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// The interactive menu. The workspace lock is never held while waiting on
+/// the user, since sessions need it to apply what peers send.
+async fn run_interactive(todo: &mut Vec<Task>, workspace: Arc<Mutex<Workspace>>, data_dir: &Path, config: &Config) {
+    let (ctx, nearby) = start_network(workspace.clone(), data_dir, config).await;
+    let shared_peers = ctx.shared_peers.clone();
+    let pairing = ctx.pairing.clone();
 
     loop {
         println!("\n1. Add a Task");
         println!("2. Remove a Task");
         println!("3. Mark a Task as done");
-        println!("4. List all tasks");
-        println!("5. Connect to a Peer");
-        println!("6. Quit");
+        println!("4. Mark a Task as not done");
+        println!("5. Edit a Task name");
+        println!("6. Edit a Task's notes");
+        println!("7. Add a subtask");
+        println!("8. Indent a task");
+        println!("9. Outdent a task");
+        println!("10. Move a task under another");
+        println!("11. Reorder a task");
+        println!("12. List all tasks");
+        println!("13. Switch to another list");
+        println!("14. Connect to a Peer");
+        println!("15. Review pairing requests");
+        println!("16. List peers and connection states");
+        println!("17. List nearby peers");
+        println!("18. Quit");
         print!("Enter your choice: ");
         stdout().flush().unwrap();
 
@@ -119,94 +393,423 @@ async fn run_interactive(todo: &mut Vec<Task>, crdt: Arc<Mutex<CrdtToDoList>>) {
                 let mut task_name = String::new();
                 stdin().read_line(&mut task_name).expect("Failed to read line.");
 
+                print!("Enter due date (YYYY-MM-DD [HH:MM], blank for none): ");
+                stdout().flush().unwrap();
+                let mut due_input = String::new();
+                stdin().read_line(&mut due_input).expect("Failed to read line.");
+                let due = if due_input.trim().is_empty() {
+                    None
+                } else {
+                    match parse_due(&due_input) {
+                        Ok(due) => Some(due),
+                        Err(e) => {
+                            println!("{}", e);
+                            continue;
+                        }
+                    }
+                };
+
+                print!("Enter priority (low/normal/high/urgent, blank for normal): ");
+                stdout().flush().unwrap();
+                let mut priority_input = String::new();
+                stdin().read_line(&mut priority_input).expect("Failed to read line.");
+                let priority = if priority_input.trim().is_empty() {
+                    Priority::Normal
+                } else {
+                    match Priority::parse(&priority_input) {
+                        Some(priority) => priority,
+                        None => {
+                            println!("Invalid priority. Please enter low, normal, high or urgent.");
+                            continue;
+                        }
+                    }
+                };
+
+                print!("Enter tags (comma separated, blank for none): ");
+                stdout().flush().unwrap();
+                let mut tags_input = String::new();
+                stdin().read_line(&mut tags_input).expect("Failed to read line.");
+
                 Task::add_task(todo, task_name.clone());
-                if let Some(task) = todo.last() {
-                    let mut crdt_guard = crdt.lock().await;
-                    let mut sync = sync_state.lock().await;
+                if let Some(task) = todo.last_mut() {
+                    task.due = due;
+                    task.priority = priority;
+                    task.tags = tags_input.split(',').map(normalize_tag).filter(|tag| !tag.is_empty()).collect();
+                    let mut workspace_guard = workspace.lock().await;
+                    let crdt_guard = workspace_guard.current_mut();
                     let peers = &shared_peers;
 
-                    match crdt_guard.add_task(task, &mut sync, &peers).await {
-                        Ok(()) => {},
+                    let result = crdt_guard.add_task(task, peers).await;
+                    update_local_list_from_crdt(crdt_guard, todo);
+                    drop(workspace_guard);
+                    match result {
+                        Ok(()) => press_enter_to_continue(),
                         Err(e) => println!("An error \"{}\" has occurred!", e),
                     }
-                    update_local_list_from_crdt(&crdt_guard, todo);
                 }
-                crdt.lock().await.save_to_file("autocommit_doc.automerge").unwrap()
+                workspace.lock().await.save().unwrap()
             },
             2 => {
-                print!("Enter task ID to remove: ");
+                print!("Enter task ID (or a unique prefix) to remove: ");
                 stdout().flush().unwrap();
                 let mut input = String::new();
                 stdin().read_line(&mut input).expect("Failed to read line.");
 
-                if let Ok(index) = input.trim().parse::<usize>() {
-                    let mut crdt_guard = crdt.lock().await;
-                    let mut sync = sync_state.lock().await;
+                let query = input.trim();
+                if !query.is_empty() {
+                    let mut workspace_guard = workspace.lock().await;
+                    let crdt_guard = workspace_guard.current_mut();
                     let peers = &shared_peers;
 
-                    match crdt_guard.remove_task(index, &mut sync, &peers).await {
-                        Ok(()) => {},
+                    let result = crdt_guard.remove_task(query, peers).await;
+                    update_local_list_from_crdt(crdt_guard, todo);
+                    drop(workspace_guard);
+                    match result {
+                        Ok(()) => press_enter_to_continue(),
                         Err(e) => println!("An error \"{}\" has occurred!", e),
                     }
-                    update_local_list_from_crdt(&crdt_guard, todo);
                 } else {
                     println!("Invalid input. Please enter a valid ID.");
                 }
-                crdt.lock().await.save_to_file("autocommit_doc.automerge").unwrap()
+                workspace.lock().await.save().unwrap()
             },
             3 => {
-                print!("Enter task ID to mark as done: ");
+                print!("Enter task ID (or a unique prefix) to mark as done: ");
                 stdout().flush().unwrap();
                 let mut input = String::new();
                 stdin().read_line(&mut input).expect("Failed to read line.");
 
-                if let Ok(index) = input.trim().parse::<usize>() {
-                    let mut crdt_guard = crdt.lock().await;
-                    let mut sync = sync_state.lock().await;
+                let query = input.trim();
+                if !query.is_empty() {
+                    let mut workspace_guard = workspace.lock().await;
+                    let crdt_guard = workspace_guard.current_mut();
                     let peers = &shared_peers;
 
-                    match crdt_guard.mark_done(index, &mut sync, &peers).await {
-                        Ok(()) => {},
-                        Err(e) => { println!("An error \"{}\" has occurred!", e) },
+                    let result = crdt_guard.mark_done(query, peers).await;
+                    update_local_list_from_crdt(crdt_guard, todo);
+                    drop(workspace_guard);
+                    match result {
+                        Ok(()) => press_enter_to_continue(),
+                        Err(e) => println!("An error \"{}\" has occurred!", e),
                     }
-                    update_local_list_from_crdt(&crdt_guard, todo);
                 } else {
                     println!("Invalid input. Please enter a valid ID.");
                 }
-                crdt.lock().await.save_to_file("autocommit_doc.automerge").unwrap()
+                workspace.lock().await.save().unwrap()
             },
             4 => {
-                show_welcome_screen();
-                let crdt_guard = crdt.lock().await;
-                update_local_list_from_crdt(&crdt_guard, todo);
-                Task::list_tasks(todo);
+                print!("Enter task ID (or a unique prefix) to mark as not done: ");
+                stdout().flush().unwrap();
+                let mut input = String::new();
+                stdin().read_line(&mut input).expect("Failed to read line.");
+
+                let query = input.trim();
+                if !query.is_empty() {
+                    let mut workspace_guard = workspace.lock().await;
+                    let crdt_guard = workspace_guard.current_mut();
+                    let peers = &shared_peers;
+
+                    let result = crdt_guard.mark_undone(query, peers).await;
+                    update_local_list_from_crdt(crdt_guard, todo);
+                    drop(workspace_guard);
+                    match result {
+                        Ok(()) => press_enter_to_continue(),
+                        Err(e) => println!("An error \"{}\" has occurred!", e),
+                    }
+                } else {
+                    println!("Invalid input. Please enter a valid ID.");
+                }
+                workspace.lock().await.save().unwrap()
             },
             5 => {
-                println!("Enter the IP Address of the Peer: ");
+                print!("Enter task ID (or a unique prefix) to edit: ");
+                stdout().flush().unwrap();
                 let mut input = String::new();
-                stdin().read_line(&mut input).expect("Failed to read the input!");
-                let ip = input.trim().to_string();
-
-                let peer_id_clone = peer_id.clone();
-                let shared_peers_clone = shared_peers.clone();
-                let crdt_clone = crdt.clone();
-                let sync_state_clone = sync_state.clone();
-
-                tokio::spawn(async move {
-                    if let Err(e) = connect_to_peer(
-                        ip.clone(),
-                        peer_id_clone,
-                        public_key,
-                        shared_peers_clone,
-                        crdt_clone,
-                        sync_state_clone,
-                    ).await {
-                        println!("Failed to connect to peer {}: {}", ip, e);
-                    }
-                });
+                stdin().read_line(&mut input).expect("Failed to read line.");
+
+                print!("Enter the new task name: ");
+                stdout().flush().unwrap();
+                let mut task_name = String::new();
+                stdin().read_line(&mut task_name).expect("Failed to read line.");
+
+                let query = input.trim();
+                let name = task_name.trim();
+                if !query.is_empty() && !name.is_empty() {
+                    let mut workspace_guard = workspace.lock().await;
+                    let crdt_guard = workspace_guard.current_mut();
+                    let peers = &shared_peers;
+
+                    let result = crdt_guard.edit_task(query, name, peers).await;
+                    update_local_list_from_crdt(crdt_guard, todo);
+                    drop(workspace_guard);
+                    match result {
+                        Ok(()) => press_enter_to_continue(),
+                        Err(e) => println!("An error \"{}\" has occurred!", e),
+                    }
+                } else {
+                    println!("Invalid input. Please enter a valid ID and name.");
+                }
+                workspace.lock().await.save().unwrap()
             },
             6 => {
-                crdt.lock().await.save_to_file("autocommit_doc.automerge").unwrap();
+                print!("Enter task ID (or a unique prefix) to edit notes for: ");
+                stdout().flush().unwrap();
+                let mut input = String::new();
+                stdin().read_line(&mut input).expect("Failed to read line.");
+
+                println!("Enter the new notes (finish with an empty line):");
+                let mut notes = String::new();
+                loop {
+                    let mut line = String::new();
+                    stdin().read_line(&mut line).expect("Failed to read line.");
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    notes.push_str(&line);
+                }
+
+                let query = input.trim();
+                if !query.is_empty() {
+                    let mut workspace_guard = workspace.lock().await;
+                    let crdt_guard = workspace_guard.current_mut();
+                    let peers = &shared_peers;
+
+                    let result = crdt_guard.set_notes(query, notes.trim_end(), peers).await;
+                    update_local_list_from_crdt(crdt_guard, todo);
+                    drop(workspace_guard);
+                    match result {
+                        Ok(()) => press_enter_to_continue(),
+                        Err(e) => println!("An error \"{}\" has occurred!", e),
+                    }
+                } else {
+                    println!("Invalid input. Please enter a valid ID.");
+                }
+                workspace.lock().await.save().unwrap()
+            },
+            7 => {
+                print!("Enter the parent task ID (or a unique prefix): ");
+                stdout().flush().unwrap();
+                let mut parent = String::new();
+                stdin().read_line(&mut parent).expect("Failed to read line.");
+
+                print!("Enter subtask name: ");
+                stdout().flush().unwrap();
+                let mut task_name = String::new();
+                stdin().read_line(&mut task_name).expect("Failed to read line.");
+
+                let parent = parent.trim();
+                if !parent.is_empty() && !task_name.trim().is_empty() {
+                    Task::add_task(todo, task_name.clone());
+                    if let Some(task) = todo.last() {
+                        let mut workspace_guard = workspace.lock().await;
+                        let crdt_guard = workspace_guard.current_mut();
+                        let peers = &shared_peers;
+
+                        let result = crdt_guard.add_subtask(parent, task, peers).await;
+                        update_local_list_from_crdt(crdt_guard, todo);
+                            drop(workspace_guard);
+                        match result {
+                            Ok(()) => press_enter_to_continue(),
+                            Err(e) => println!("An error \"{}\" has occurred!", e),
+                        }
+                    }
+                } else {
+                    println!("Invalid input. Please enter a valid ID and name.");
+                }
+                workspace.lock().await.save().unwrap()
+            },
+            8 | 9 => {
+                let action = if choice == 8 { "indent" } else { "outdent" };
+                print!("Enter task ID (or a unique prefix) to {}: ", action);
+                stdout().flush().unwrap();
+                let mut input = String::new();
+                stdin().read_line(&mut input).expect("Failed to read line.");
+
+                let query = input.trim();
+                if !query.is_empty() {
+                    let mut workspace_guard = workspace.lock().await;
+                    let crdt_guard = workspace_guard.current_mut();
+                    let peers = &shared_peers;
+
+                    let result = if choice == 8 {
+                        crdt_guard.indent_task(query, peers).await
+                    } else {
+                        crdt_guard.outdent_task(query, peers).await
+                    };
+                    update_local_list_from_crdt(crdt_guard, todo);
+                    drop(workspace_guard);
+                    match result {
+                        Ok(()) => press_enter_to_continue(),
+                        Err(e) => println!("An error \"{}\" has occurred!", e),
+                    }
+                } else {
+                    println!("Invalid input. Please enter a valid ID.");
+                }
+                workspace.lock().await.save().unwrap()
+            },
+            10 => {
+                print!("Enter task ID (or a unique prefix) to move: ");
+                stdout().flush().unwrap();
+                let mut input = String::new();
+                stdin().read_line(&mut input).expect("Failed to read line.");
+
+                print!("Enter the new parent task ID (or a unique prefix): ");
+                stdout().flush().unwrap();
+                let mut parent = String::new();
+                stdin().read_line(&mut parent).expect("Failed to read line.");
+
+                let query = input.trim();
+                let parent = parent.trim();
+                if !query.is_empty() && !parent.is_empty() {
+                    let mut workspace_guard = workspace.lock().await;
+                    let crdt_guard = workspace_guard.current_mut();
+                    let peers = &shared_peers;
+
+                    let result = crdt_guard.move_task_under(query, parent, peers).await;
+                    update_local_list_from_crdt(crdt_guard, todo);
+                    drop(workspace_guard);
+                    match result {
+                        Ok(()) => press_enter_to_continue(),
+                        Err(e) => println!("An error \"{}\" has occurred!", e),
+                    }
+                } else {
+                    println!("Invalid input. Please enter valid IDs.");
+                }
+                workspace.lock().await.save().unwrap()
+            },
+            11 => {
+                print!("Enter task ID (or a unique prefix) to move: ");
+                stdout().flush().unwrap();
+                let mut input = String::new();
+                stdin().read_line(&mut input).expect("Failed to read line.");
+
+                print!("Move it where? (top, bottom, before <ID>, after <ID>): ");
+                stdout().flush().unwrap();
+                let mut where_input = String::new();
+                stdin().read_line(&mut where_input).expect("Failed to read line.");
+
+                let mut words = where_input.split_whitespace();
+                let placement = match (words.next(), words.next()) {
+                    (Some("top"), None) => Placement::Top,
+                    (Some("bottom"), None) => Placement::Bottom,
+                    (Some("before"), Some(target)) => Placement::Before(target.to_string()),
+                    (Some("after"), Some(target)) => Placement::After(target.to_string()),
+                    _ => {
+                        println!("Invalid input. Please enter top, bottom, before <ID> or after <ID>.");
+                        continue;
+                    }
+                };
+
+                let query = input.trim();
+                if !query.is_empty() {
+                    let mut workspace_guard = workspace.lock().await;
+                    let crdt_guard = workspace_guard.current_mut();
+                    let peers = &shared_peers;
+
+                    let result = crdt_guard.move_task(query, &placement, peers).await;
+                    update_local_list_from_crdt(crdt_guard, todo);
+                    drop(workspace_guard);
+                    match result {
+                        Ok(()) => press_enter_to_continue(),
+                        Err(e) => println!("An error \"{}\" has occurred!", e),
+                    }
+                } else {
+                    println!("Invalid input. Please enter a valid ID.");
+                }
+                workspace.lock().await.save().unwrap()
+            },
+            12 => {
+                show_welcome_screen();
+                let workspace_guard = workspace.lock().await;
+                println!("List: {}", workspace_guard.current_name());
+                update_local_list_from_crdt(workspace_guard.current(), todo);
+                drop(workspace_guard);
+                Task::list_tasks(todo, &ListOptions::default());
+            },
+            13 => {
+                let summaries = workspace.lock().await.summaries();
+                for list in summaries {
+                    let marker = if list.current { "*" } else { " " };
+                    println!("{} {} ({} tasks)", marker, list.name, list.tasks);
+                }
+                print!("Enter the list to switch to: ");
+                stdout().flush().unwrap();
+                let mut input = String::new();
+                stdin().read_line(&mut input).expect("Failed to read line.");
+
+                let mut workspace_guard = workspace.lock().await;
+                match workspace_guard.switch(input.trim()) {
+                    Ok(()) => {
+                        println!("Switched to list '{}'", workspace_guard.current_name());
+                        update_local_list_from_crdt(workspace_guard.current(), todo);
+                    }
+                    Err(e) => println!("{}", e),
+                }
+                workspace_guard.save().unwrap()
+            },
+            14 => {
+                println!("Enter the peer address (host, host:port or [IPv6]:port) or a nearby peer id: ");
+                let mut input = String::new();
+                stdin().read_line(&mut input).expect("Failed to read the input!");
+                let mut ip = input.trim().to_string();
+
+                let nearby_matches: Vec<_> = nearby_peers(&nearby)
+                    .await
+                    .into_iter()
+                    .filter(|(peer_id, _)| !ip.is_empty() && peer_id.starts_with(&ip))
+                    .collect();
+                if let [(_, peer)] = nearby_matches.as_slice() {
+                    ip = peer.address.to_string();
+                }
+
+                tokio::spawn(maintain(ip, ctx.clone()));
+            },
+            15 => {
+                review_pairing_requests(&pairing, &ctx.identity).await;
+            },
+            16 => {
+                let peers = shared_peers.lock().await;
+                let links = ctx.links.lock().await;
+                if peers.is_empty() && links.is_empty() {
+                    println!("No peers connected.");
+                }
+                for peer in peers.values() {
+                    let rtt = peer.rtt.map_or_else(|| "rtt -".to_string(), |rtt| format!("rtt {}ms", rtt.as_millis()));
+                    println!(
+                        "{}  {}  connected  {}  seen {}s ago  fingerprint {}",
+                        peer.peer_id.id,
+                        peer.address,
+                        rtt,
+                        peer.last_seen.elapsed().as_secs(),
+                        fingerprint(&peer.public_key)
+                    );
+                }
+                for (target, state) in links.iter() {
+                    if !matches!(state, LinkState::Connected(_)) {
+                        println!("{}  {}", target, state);
+                    }
+                }
+            },
+            17 => {
+                let peers = nearby_peers(&nearby).await;
+                if peers.is_empty() {
+                    println!("No nearby peers found.");
+                }
+                let trust = ctx.trust.lock().await;
+                let connected = shared_peers.lock().await;
+                for (peer_id, peer) in peers {
+                    let state = if connected.contains_key(&peer::PeerId { id: peer_id.clone() }) {
+                        "connected"
+                    } else if trust.peers.contains_key(&peer_id) {
+                        "trusted"
+                    } else {
+                        "unknown"
+                    };
+                    println!("{}  {}  {}  seen {}s ago", peer_id, peer.address, state, peer.last_seen.elapsed().as_secs());
+                }
+            },
+            18 => {
+                workspace.lock().await.save().unwrap();
                 show_welcome_screen_exit();
                 break;
             },
@@ -215,4 +818,188 @@ async fn run_interactive(todo: &mut Vec<Task>, crdt: Arc<Mutex<CrdtToDoList>>) {
             },
         }
     }
-}
\ No newline at end of file
+}
+
+/// Walks through pending pairing requests, showing our own fingerprint
+/// alongside the peer's so the user can compare both ends.
+async fn review_pairing_requests(pairing: &PairingRequests, identity: &Identity) {
+    let requests: Vec<_> = pairing.lock().await.drain(..).filter(|r| r.is_pending()).collect();
+    if requests.is_empty() {
+        println!("No pending pairing requests.");
+        return;
+    }
+
+    for request in requests {
+        println!("\nPeer '{}' at {}", request.peer_id.id, request.address);
+        println!("Their fingerprint: {}", fingerprint(&request.public_key));
+        println!("Your fingerprint:  {}", fingerprint(&identity.public_key));
+        print!("Does this match the fingerprint shown on the other device? [y/N]: ");
+        stdout().flush().unwrap();
+
+        let mut input = String::new();
+        stdin().read_line(&mut input).expect("Failed to read line.");
+        request.respond(input.trim().eq_ignore_ascii_case("y"));
+    }
+}
+
+/// Reads the config file, exiting if it is malformed, and applies the
+/// command-line flags on top of it.
+fn load_config(cli: &Cli, path: &Path) -> Config {
+    let mut config = Config::load(path).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    if !cli.listen.is_empty() {
+        config.listen = cli.listen.clone();
+    }
+    if let Some(port) = cli.connect_port {
+        config.connect_port = port;
+    }
+    if let Some(data_dir) = &cli.data_dir {
+        config.data_dir = Some(data_dir.clone());
+    }
+    if cli.no_color {
+        config.color = false;
+    }
+    if cli.no_banner {
+        config.banner = false;
+    }
+    if cli.no_discovery {
+        config.discovery = false;
+    }
+    if let Some(policy) = cli.trust_policy {
+        config.trust_policy = Some(policy);
+    }
+    config
+}
+
+fn run_config_command(action: &ConfigCommands, path: &Path) -> std::io::Result<()> {
+    let mut config = Config::load(path)?;
+
+    match action {
+        ConfigCommands::Show => {
+            println!("# {}", path.display());
+            print!("{}", config.to_toml()?);
+        }
+
+        ConfigCommands::Get { key } => {
+            println!("{}", config.get(key)?);
+        }
+
+        ConfigCommands::Set { key, value } => {
+            config.set(key, value)?;
+            config.save(path)?;
+            println!("{} = {}", key, config.get(key)?);
+        }
+
+        ConfigCommands::Unset { key } => {
+            config.unset(key)?;
+            config.save(path)?;
+            println!("{} = {}", key, config.get(key)?);
+        }
+    }
+
+    Ok(())
+}
+
+/// Older versions kept the document in the current directory. Point at it
+/// rather than silently starting from an empty list.
+fn warn_about_local_document(list_file: &Path) {
+    let legacy = Path::new("autocommit_doc.automerge");
+    if !list_file.exists() && legacy.exists() {
+        eprintln!(
+            "Found {} in the current directory, but tasks are now kept in {}. \
+             Move it there, or pass --file {} to keep using it.",
+            legacy.display(),
+            list_file.display(),
+            legacy.display()
+        );
+    }
+}
+
+fn run_peers_command(action: &PeerCommands, data_dir: &Path, config: &Config) -> std::io::Result<()> {
+    let mut store = TrustStore::load(&TrustStore::path_in(data_dir))?;
+    store.policy_override = config.trust_policy;
+    let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
+
+    match action {
+        PeerCommands::Trust { public_key, name } => {
+            let key = decode_public_key(public_key).map_err(invalid)?;
+            let peer_id = store.update(|store| store.trust(&key, name.clone()))?;
+            println!("Trusted {} (fingerprint {})", peer_id.id, fingerprint(&key));
+        }
+
+        PeerCommands::Revoke { peer_id } => {
+            let removed = store.update(|store| store.revoke(peer_id))?.map_err(invalid)?;
+            println!("Revoked {}", removed);
+        }
+
+        PeerCommands::ListTrusted => {
+            if store.peers.is_empty() {
+                println!("No trusted peers.");
+            }
+            for (peer_id, peer) in &store.peers {
+                let fp = decode_public_key(&peer.public_key).map(|k| fingerprint(&k)).unwrap_or_default();
+                println!("{}  {}  {}", peer_id, fp, peer.name.as_deref().unwrap_or(""));
+            }
+        }
+
+        PeerCommands::Policy { policy } => {
+            if let Some(policy) = policy {
+                store.update(|store| store.policy = *policy)?;
+            }
+            println!("Unknown peers: {}", store.policy);
+            if store.effective_policy() != store.policy {
+                println!("(overridden by the config file or command line: {})", store.effective_policy());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn run_identity_command(action: &IdentityCommands, data_dir: &Path) -> std::io::Result<()> {
+    let path = Identity::path_in(data_dir);
+
+    match action {
+        IdentityCommands::Show => {
+            let identity = Identity::load_or_generate(&path)?;
+            println!("Peer ID:     {}", identity.derive_peer_id().id);
+            println!("Public key:  {}", general_purpose::STANDARD.encode(identity.public_key));
+            println!("Fingerprint: {}", fingerprint(&identity.public_key));
+            println!("Keyfile:     {}", path.display());
+        }
+
+        IdentityCommands::New { force } => {
+            refuse_to_overwrite(&path, *force)?;
+            let identity = Identity::generate();
+            identity.save(&path)?;
+            println!("Generated new identity {}", identity.derive_peer_id().id);
+        }
+
+        IdentityCommands::Export { path: target } => {
+            let identity = Identity::load_or_generate(&path)?;
+            identity.save(target)?;
+            println!("Exported identity {} to {}", identity.derive_peer_id().id, target.display());
+        }
+
+        IdentityCommands::Import { path: source, force } => {
+            let identity = Identity::load(source)?;
+            refuse_to_overwrite(&path, *force)?;
+            identity.save(&path)?;
+            println!("Imported identity {}", identity.derive_peer_id().id);
+        }
+    }
+
+    Ok(())
+}
+
+fn refuse_to_overwrite(path: &std::path::Path, force: bool) -> std::io::Result<()> {
+    if path.exists() && !force {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("An identity already exists at {}; pass --force to replace it", path.display()),
+        ));
+    }
+    Ok(())
+}
@@ -1,261 +1,775 @@
-use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::net::{Ipv6Addr, SocketAddr};
 use std::str;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
-use tokio::sync::mpsc;
+use std::time::{Duration, Instant};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{lookup_host, TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot, watch, Mutex};
 use base64::engine::general_purpose;
 use base64::Engine as _;
-use crate::crdt::CrdtToDoList;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use crate::identity::{peer_id_from_public_key, Identity};
 use crate::peer::{Peer, PeerId, SharedPeers};
-use crate::sync::SyncState;
+use crate::reconnect::{LinkState, LinkStates, SharedKnownPeers};
+use crate::sync::{drop_stalled, sync_peer};
+use crate::transport;
+use crate::wire::{self, Decoder, Encoding};
+use crate::trust::{fingerprint, PairingRequest, PairingRequests, SharedTrustStore, TrustPolicy};
+use crate::workspace::Workspace;
 use serde::{Deserialize, Serialize};
 
+/// Domain separator for handshake signatures, so they can't be replayed as
+/// signatures over anything else.
+const AUTH_CONTEXT: &[u8] = b"rustytasks-auth-v1";
+const NONCE_LENGTH: usize = 32;
+/// How long an unknown peer may wait for the user to confirm pairing.
+const PAIRING_TIMEOUT: Duration = Duration::from_secs(120);
+/// How long the encrypted channel may take to set up.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a peer then has to send its `Hello` and `Auth`. Any host can
+/// set up the encrypted channel, so this keeps strangers from holding
+/// connections open. Waiting for the user to confirm pairing doesn't count.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(30);
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+/// Pings in a row a peer may leave unanswered before it is dropped.
+const MAX_MISSED_PONGS: u32 = 3;
+
+/// Everything a connection needs access to, shared between the listener and
+/// outgoing connections.
+#[derive(Clone)]
+pub struct NetworkContext {
+    pub identity: Arc<Identity>,
+    pub shared_peers: SharedPeers,
+    pub workspace: Arc<Mutex<Workspace>>,
+    pub trust: SharedTrustStore,
+    pub pairing: PairingRequests,
+    /// Addresses to accept peers on.
+    pub listen_addrs: Vec<String>,
+    /// Port to connect to when a peer is given without one.
+    pub connect_port: u16,
+    /// Peers we have dialed before, remembered across runs.
+    pub known_peers: SharedKnownPeers,
+    /// State of each outgoing connection, by the address it was dialed at.
+    pub links: LinkStates,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(tag = "type", content = "data")]
 pub enum Message {
+    /// Always sent as a JSON line, since the encoding is only settled once
+    /// both sides have seen each other's. Peers that predate versioning
+    /// send neither `version` nor `capabilities`.
     Hello {
         peer_id: String,
         public_key: String,
+        nonce: String,
+        #[serde(default = "legacy_version")]
+        version: u32,
+        #[serde(default)]
+        capabilities: Vec<String>,
+    },
+    Auth {
+        signature: String,
+    },
+    /// An encoded Automerge sync protocol message for the list `list`.
+    Sync {
+        list: String,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    /// Heartbeat; answered with a `Pong` carrying the same `id`.
+    Ping {
+        id: u64,
+    },
+    Pong {
+        id: u64,
     },
-    Changes(Vec<Vec<u8>>),
-    // Ping,
-    // Pong,
 }
 
-async fn verify_handshake(
+fn legacy_version() -> u32 {
+    1
+}
+
+/// Per-connection handshake progress. Each side sends a `Hello` carrying a
+/// fresh nonce and answers the other side's `Hello` with an `Auth` message
+/// signing that nonce together with the Noise handshake hash, which ties the
+/// identity to the encrypted channel it was proven on. A peer is only
+/// registered once its `Auth` checks out.
+struct Handshake {
+    local_nonce: [u8; NONCE_LENGTH],
+    handshake_hash: Vec<u8>,
+    remote_static: [u8; 32],
+    claimed: Option<(PeerId, VerifyingKey)>,
+    authenticated: bool,
+}
+
+impl Handshake {
+    fn new(handshake_hash: Vec<u8>, remote_static: [u8; 32]) -> Self {
+        let mut local_nonce = [0u8; NONCE_LENGTH];
+        OsRng.fill_bytes(&mut local_nonce);
+        Handshake { local_nonce, handshake_hash, remote_static, claimed: None, authenticated: false }
+    }
+
+    fn hello(&self, identity: &Identity) -> Message {
+        Message::Hello {
+            peer_id: identity.derive_peer_id().id,
+            public_key: general_purpose::STANDARD.encode(identity.public_key),
+            nonce: general_purpose::STANDARD.encode(self.local_nonce),
+            version: wire::PROTOCOL_VERSION,
+            capabilities: wire::capabilities(),
+        }
+    }
+}
+
+fn auth_payload(handshake_hash: &[u8], nonce: &[u8], signer: &PeerId) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(AUTH_CONTEXT.len() + handshake_hash.len() + nonce.len() + signer.id.len());
+    payload.extend_from_slice(AUTH_CONTEXT);
+    payload.extend_from_slice(handshake_hash);
+    payload.extend_from_slice(nonce);
+    payload.extend_from_slice(signer.id.as_bytes());
+    payload
+}
+
+/// Checks a peer's `Hello` and returns the `Auth` reply proving that we hold
+/// our own private key. Fails if the claimed peer id is not derived from the
+/// claimed public key, or if that key is not the one the encrypted channel
+/// was established with.
+fn answer_hello(
     msg: &Message,
-    socket_addr: SocketAddr,
-    tx: &mpsc::Sender<Message>,
-    shared_peers: &SharedPeers,
-) -> Option<()> {
-    if let Message::Hello { peer_id, public_key } = msg {
-        // Decode base64 public key (optional step)
-        let pk_bytes = general_purpose::STANDARD
-            .decode(public_key)
-            .ok()
-            .filter(|b| b.len() == 32)?;
-        let mut key = [0u8; 32];
-        key.copy_from_slice(&pk_bytes);
-
-        let peer = Peer {
-            peer_id: PeerId { id: peer_id.clone() },
-            address: socket_addr,
-            public_key: key,
-            sender: Some(tx.clone()),
-        };
+    handshake: &mut Handshake,
+    identity: &Identity,
+) -> Result<Message, String> {
+    let Message::Hello { peer_id, public_key, nonce, .. } = msg else {
+        return Err("expected Hello".to_string());
+    };
+    if handshake.claimed.is_some() {
+        return Err("duplicate Hello".to_string());
+    }
+
+    let key: [u8; 32] = general_purpose::STANDARD
+        .decode(public_key)
+        .ok()
+        .and_then(|b| b.try_into().ok())
+        .ok_or("malformed public key")?;
+    let verifying_key = VerifyingKey::from_bytes(&key).map_err(|_| "invalid public key")?;
 
-        let mut peers = shared_peers.lock().await;
-        peers.insert(peer.peer_id.clone(), peer);
+    if verifying_key.to_montgomery().to_bytes() != handshake.remote_static {
+        return Err("public key does not match the encrypted channel".to_string());
+    }
 
-        println!("Registered peer '{}' from {}", peer_id, socket_addr);
-        Some(())
-    } else {
-        None
+    let derived = peer_id_from_public_key(&key);
+    if derived.id != *peer_id {
+        return Err(format!("peer id '{}' does not match its public key", peer_id));
     }
+
+    let nonce = general_purpose::STANDARD
+        .decode(nonce)
+        .ok()
+        .filter(|n| n.len() == NONCE_LENGTH)
+        .ok_or("malformed nonce")?;
+
+    handshake.claimed = Some((derived, verifying_key));
+
+    let signature = identity.sign(&auth_payload(&handshake.handshake_hash, &nonce, &identity.derive_peer_id()));
+    Ok(Message::Auth {
+        signature: general_purpose::STANDARD.encode(signature.to_bytes()),
+    })
 }
 
-pub async fn connect_to_peer(
-    target_ip: String,
-    local_peer_id: PeerId,
-    public_key: [u8; 32],
-    shared_peers: SharedPeers,
-    crdt: Arc<Mutex<CrdtToDoList>>,
-    sync_state: Arc<Mutex<SyncState>>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let port = 58008;
-    let addr: SocketAddr = format!("{}:{}", target_ip, port).parse()?;
-    println!("Connecting to {}", addr);
-
-    let stream = TcpStream::connect(addr).await?;
-    let (mut reader, mut writer) = stream.into_split();
-    let (tx, mut rx) = mpsc::channel::<Message>(100);
+/// Checks a peer's `Auth` against the nonce we sent and against the trust
+/// policy and, if both pass, registers the peer in `shared_peers`. A peer
+/// that already has a session is turned away, unless this session is the
+/// one both ends prefer. A peer we dialed at `dialed`
+/// is remembered at that address.
+async fn verify_handshake(
+    msg: &Message,
+    handshake: &mut Handshake,
+    socket_addr: SocketAddr,
+    dialed: Option<&str>,
+    tx: &mpsc::Sender<Message>,
+    ctx: &NetworkContext,
+) -> Result<(), String> {
+    let Message::Auth { signature } = msg else {
+        return Err("expected Auth".to_string());
+    };
+    if handshake.authenticated {
+        return Err("duplicate Auth".to_string());
+    }
+    let (peer_id, verifying_key) = handshake.claimed.clone().ok_or("Auth received before Hello")?;
+
+    let signature: [u8; 64] = general_purpose::STANDARD
+        .decode(signature)
+        .ok()
+        .and_then(|b| b.try_into().ok())
+        .ok_or("malformed signature")?;
+    verifying_key
+        .verify(
+            &auth_payload(&handshake.handshake_hash, &handshake.local_nonce, &peer_id),
+            &Signature::from_bytes(&signature),
+        )
+        .map_err(|_| format!("peer '{}' failed the challenge", peer_id.id))?;
 
-    // Send a simple Hello
-    let hello = Message::Hello {
-        peer_id: local_peer_id.id.clone(),
-        public_key: general_purpose::STANDARD.encode(public_key),
+    let public_key = verifying_key.to_bytes();
+    check_trust(&peer_id, &public_key, socket_addr, ctx).await?;
+
+    let shared_lists = ctx.workspace.lock().await.scope_for(&peer_id.id);
+    let peer = Peer {
+        peer_id: peer_id.clone(),
+        address: socket_addr,
+        outgoing: dialed.is_some(),
+        public_key,
+        sender: Some(tx.clone()),
+        sync_states: HashMap::new(),
+        shared_lists,
+        last_seen: Instant::now(),
+        rtt: None,
+        pending_ping: None,
     };
-    let mut buf = serde_json::to_vec(&hello)?;
-    buf.push(b'\n');
-    writer.write_all(&buf).await?;
-    writer.flush().await?;
-
-    // Register ourselves locally
-    {
-        let mut peers = shared_peers.lock().await;
-        peers.insert(local_peer_id.clone(), Peer {
-            peer_id: local_peer_id.clone(),
-            address: addr,
-            public_key,
-            sender: Some(tx.clone()),
-        });
+
+    // Both sides may dial each other at once. Both ends then keep the
+    // session dialed by the lower peer id, as discovery does, and turn the
+    // other away; the session it replaces ends at its next heartbeat.
+    let lower_dialed = |outgoing: bool| outgoing == (ctx.identity.derive_peer_id().id < peer_id.id);
+    let mut peers = ctx.shared_peers.lock().await;
+    if let Some(existing) = peers.get(&peer_id) {
+        if lower_dialed(existing.outgoing) || !lower_dialed(peer.outgoing) {
+            drop(peers);
+            if let Some(target) = dialed {
+                ctx.links.lock().await.insert(target.to_string(), LinkState::Connected(peer_id.clone()));
+            }
+            return Err(format!("peer '{}' is already connected", peer_id.id));
+        }
     }
+    peers.insert(peer.peer_id.clone(), peer);
+    drop(peers);
+    handshake.authenticated = true;
 
-    // Task to send outgoing messages
-    tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            let mut out = serde_json::to_vec(&msg).unwrap();
-            out.push(b'\n');
-            if writer.write_all(&out).await.is_err() { break; }
+    if let Some(target) = dialed {
+        ctx.links.lock().await.insert(target.to_string(), LinkState::Connected(peer_id.clone()));
+        if let Err(e) = ctx.known_peers.lock().await.remember(&peer_id, target) {
+            eprintln!("Failed to save the address of peer {}: {}", peer_id.id, e);
         }
-    });
+    }
 
-    // Send periodic Pings
-    // {
-    //     let ping_tx = tx.clone();
-    //     tokio::spawn(async move {
-    //         let mut interval = tokio::time::interval(std::time::Duration::from_secs(30));
-    //         loop {
-    //             interval.tick().await;
-    //             if ping_tx.send(Message::Ping).await.is_err() { break; }
-    //         }
-    //     });
-    // }
-
-    // Read & handle incoming messages
-    let mut buffer = vec![0; 4096];
-    let mut acc = Vec::new();
-    loop {
-        let n = reader.read(&mut buffer).await?;
-        if n == 0 {
-            println!("Disconnected from {}", addr);
-            break;
+    println!("Registered peer '{}' from {}", peer_id.id, socket_addr);
+    Ok(())
+}
+
+/// Applies the trust policy to an authenticated peer. With the `prompt`
+/// policy an unknown peer is parked until the user confirms its fingerprint
+/// from the interactive menu; confirmed peers are added to the trust store.
+async fn check_trust(
+    peer_id: &PeerId,
+    public_key: &[u8; 32],
+    socket_addr: SocketAddr,
+    ctx: &NetworkContext,
+) -> Result<(), String> {
+    let policy = {
+        let mut trust = ctx.trust.lock().await;
+        trust.refresh();
+        if trust.is_trusted(public_key) {
+            return Ok(());
         }
-        acc.extend_from_slice(&buffer[..n]);
-        while let Some(pos) = acc.iter().position(|&b| b == b'\n') {
-            let line = acc.drain(..pos).collect::<Vec<_>>();
-            acc.drain(..1); // remove newline
-            if let Ok(text) = str::from_utf8(&line) {
-                if let Ok(msg) = serde_json::from_str::<Message>(text) {
-                    match msg {
-                        Message::Hello { .. } => {
-                            if verify_handshake(&msg, addr, &tx, &shared_peers).await.is_some() {
-                                let mut crdt = crdt.lock().await;
-                                let mut st   = sync_state.lock().await;
-                                crdt.send_changes(&mut st, &shared_peers).await;
-                            }
-                        }
-                        Message::Changes(chs) => {
-                            let mut crdt = crdt.lock().await;
-                            let mut st   = sync_state.lock().await;
-                            crdt.apply_changes_from_bytes(chs, &mut st).await;
-                        }
-                        // Message::Ping => {
-                        //     let _ = tx.send(Message::Pong).await;
-                        // }
-                        // Message::Pong => {
-                        //     println!("Pong from {}", addr);
-                        // }
-                    }
+        trust.effective_policy()
+    };
+
+    match policy {
+        TrustPolicy::Accept => Ok(()),
+        TrustPolicy::Reject => Err(format!("peer '{}' is not trusted", peer_id.id)),
+        TrustPolicy::Prompt => {
+            let (request, decision) = PairingRequest::new(peer_id.clone(), *public_key, socket_addr);
+            ctx.pairing.lock().await.push(request);
+            println!(
+                "\nPairing request from '{}' at {} (fingerprint {}). Choose \"Review pairing requests\" to confirm.",
+                peer_id.id, socket_addr, fingerprint(public_key)
+            );
+
+            match tokio::time::timeout(PAIRING_TIMEOUT, decision).await {
+                Ok(Ok(true)) => {
+                    let mut trust = ctx.trust.lock().await;
+                    trust
+                        .update(|trust| trust.trust(public_key, None))
+                        .map_err(|e| format!("failed to save trust store: {}", e))?;
+                    println!("Paired with '{}'", peer_id.id);
+                    Ok(())
                 }
+                Ok(_) => Err(format!("pairing with '{}' was declined", peer_id.id)),
+                Err(_) => Err(format!("pairing with '{}' timed out", peer_id.id)),
+            }
+        }
+    }
+}
+
+/// Splits `host:port`, `[v6]:port`, a bare IPv6 literal or a bare host
+/// name into host and port, using `default_port` when none is given.
+pub fn split_host_port(target: &str, default_port: u16) -> Result<(String, u16), String> {
+    let target = target.trim();
+    let parse_port = |port: &str| port.parse::<u16>().map_err(|_| format!("invalid port '{}'", port));
+
+    if let Some(rest) = target.strip_prefix('[') {
+        let (host, after) = rest.split_once(']').ok_or_else(|| format!("missing ']' in '{}'", target))?;
+        let port = match after.strip_prefix(':') {
+            Some(port) => parse_port(port)?,
+            None if after.is_empty() => default_port,
+            None => return Err(format!("unexpected '{}' after ']'", after)),
+        };
+        return Ok((host.to_string(), port));
+    }
+    if target.parse::<Ipv6Addr>().is_ok() {
+        return Ok((target.to_string(), default_port));
+    }
+    match target.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') => Ok((host.to_string(), parse_port(port)?)),
+        Some(_) => Err(format!("IPv6 addresses with a port need brackets, as in [{}]:{}", target, default_port)),
+        None if target.is_empty() => Err("no address given".to_string()),
+        None => Ok((target.to_string(), default_port)),
+    }
+}
+
+/// Resolves an address given as in `split_host_port`, including DNS names.
+async fn resolve(target: &str, default_port: u16) -> Result<Vec<SocketAddr>, Box<dyn std::error::Error + Send + Sync>> {
+    let (host, port) = split_host_port(target, default_port)?;
+    let addrs: Vec<SocketAddr> = lookup_host((host.as_str(), port)).await?.collect();
+    if addrs.is_empty() {
+        return Err(format!("'{}' did not resolve to any address", host).into());
+    }
+    Ok(addrs)
+}
+
+pub async fn connect_to_peer(
+    target: String,
+    ctx: NetworkContext,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // A name may resolve to several addresses (IPv4 and IPv6); use the
+    // first one that accepts.
+    let mut last_error = None;
+    for addr in resolve(&target, ctx.connect_port).await? {
+        println!("Connecting to {}", addr);
+        match TcpStream::connect(addr).await {
+            Ok(stream) => {
+                run_session(stream, addr, ctx, Some(&target)).await?;
+                println!("Disconnected from {}", addr);
+                return Ok(());
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.map_or_else(|| "no address to connect to".into(), Into::into))
+}
+
+/// Binds one listening socket. IPv6 sockets are IPv6-only so that an IPv4
+/// socket on the same port can sit next to them; listening on both gives
+/// dual-stack on every platform.
+fn bind(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Accepts peers on every configured address. Addresses that can't be bound
+/// (say, IPv6 on a host without it) are reported and skipped; it is only an
+/// error if none can be.
+pub async fn connections(ctx: NetworkContext) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut listeners = Vec::new();
+    for target in &ctx.listen_addrs {
+        let addrs = match resolve(target, ctx.connect_port).await {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                println!("Can't listen on {}: {}", target, e);
+                continue;
+            }
+        };
+        for addr in addrs {
+            match bind(addr) {
+                Ok(listener) => listeners.push(listener),
+                Err(e) => println!("Can't listen on {}: {}", addr, e),
             }
         }
     }
+    if listeners.is_empty() {
+        return Err("no listen address could be bound".into());
+    }
 
-    // on disconnect, remove peer
-    let mut peers = shared_peers.lock().await;
-    peers.retain(|_, p| p.address != addr);
-    println!("Unregistered peer {}", addr);
+    let mut accepting = tokio::task::JoinSet::new();
+    for listener in listeners {
+        accepting.spawn(accept_loop(listener, ctx.clone()));
+    }
+    while let Some(result) = accepting.join_next().await {
+        result??;
+    }
     Ok(())
 }
 
-pub async fn connections(
-    shared_peers: SharedPeers,
-    crdt: Arc<Mutex<CrdtToDoList>>,
-    sync_state: Arc<Mutex<SyncState>>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let listener = TcpListener::bind("0.0.0.0:58008").await?;
+async fn accept_loop(listener: TcpListener, ctx: NetworkContext) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     loop {
         let (socket, addr) = listener.accept().await?;
-        let sp = shared_peers.clone();
-        let cd = crdt.clone();
-        let ss = sync_state.clone();
+        let ctx = ctx.clone();
         tokio::spawn(async move {
-            handle_connection(socket, addr, sp, cd, ss).await;
+            if let Err(e) = run_session(socket, addr, ctx, None).await {
+                println!("Connection from {} closed: {}", addr, e);
+            }
         });
     }
 }
 
-async fn handle_connection(
+/// Drives one connection, in either direction: sets up the encrypted
+/// channel, performs the handshake, then exchanges changes until the socket
+/// closes or the peer misbehaves. `dialed` is the address an outgoing
+/// connection was made to, and `None` for incoming ones.
+async fn run_session(
     stream: TcpStream,
     addr: SocketAddr,
-    shared_peers: SharedPeers,
-    crdt: Arc<Mutex<CrdtToDoList>>,
-    sync_state: Arc<Mutex<SyncState>>,
-) {
-    // identical to the read/write loop in connect_to_peer,
-    // minus the initial "send Hello". Just call verify_handshake
-    // on the first Hello you receive.
-    use tokio::io::AsyncReadExt;
-    use tokio::io::AsyncWriteExt;
-
-    let (mut reader, mut writer) = stream.into_split();
+    ctx: NetworkContext,
+    dialed: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // Frames are small and written in pieces; don't let Nagle hold them back.
+    stream.set_nodelay(true)?;
+    let channel = tokio::time::timeout(HANDSHAKE_TIMEOUT, transport::handshake(stream, &ctx.identity, dialed.is_some()))
+        .await
+        .map_err(|_| format!("{} did not complete the handshake", addr))??;
+    let (mut reader, mut writer) = (channel.reader, channel.writer);
     let (tx, mut rx) = mpsc::channel::<Message>(100);
+    let (encoding_tx, encoding_rx) = watch::channel(Encoding::JsonLines);
 
-    // spawn writer task
+    // Task to send outgoing messages
     tokio::spawn(async move {
         while let Some(msg) = rx.recv().await {
-            let mut out = serde_json::to_vec(&msg).unwrap();
-            out.push(b'\n');
-            if writer.write_all(&out).await.is_err() { break; }
+            let encoding = match msg {
+                Message::Hello { .. } => Encoding::JsonLines,
+                _ => *encoding_rx.borrow(),
+            };
+            let out = match wire::encode(&msg, encoding) {
+                Ok(out) => out,
+                Err(e) => {
+                    eprintln!("Not sending to {}: {}", addr, e);
+                    continue;
+                }
+            };
+            if writer.send(&out).await.is_err() { break; }
         }
     });
 
-    // spawn ping task
-    // {
-    //     let ping_tx = tx.clone();
-    //     tokio::spawn(async move {
-    //         let mut interval = tokio::time::interval(std::time::Duration::from_secs(30));
-    //         loop {
-    //             interval.tick().await;
-    //             if ping_tx.send(Message::Ping).await.is_err() { break; }
-    //         }
-    //     });
-    // }
-
-    // reader loop (same as above)
-    let mut buffer = vec![0; 4096];
-    let mut acc = Vec::new();
-    loop {
-        match reader.read(&mut buffer).await {
-            Ok(0) => break,
-            Ok(n) => {
-                acc.extend_from_slice(&buffer[..n]);
-                while let Some(pos) = acc.iter().position(|&b| b == b'\n') {
-                    let line = acc.drain(..pos).collect::<Vec<_>>();
-                    acc.drain(..1);
-                    if let Ok(text) = str::from_utf8(&line) {
-                        if let Ok(msg) = serde_json::from_str::<Message>(text) {
-                            match msg {
-                                Message::Hello { .. } => {
-                                    if verify_handshake(&msg, addr, &tx, &shared_peers).await.is_some() {
-                                        let mut crdt = crdt.lock().await;
-                                        let mut st   = sync_state.lock().await;
-                                        crdt.send_changes(&mut st, &shared_peers).await;
-                                    }
-                                }
-                                Message::Changes(chs) => {
-                                    let mut crdt = crdt.lock().await;
-                                    let mut st   = sync_state.lock().await;
-                                    crdt.apply_changes_from_bytes(chs, &mut st).await;
-                                }
-                                // Message::Ping => {
-                                //     let _ = tx.send(Message::Pong).await;
-                                // }
-                                // Message::Pong => {}
-                            }
+    let mut handshake = Handshake::new(channel.handshake_hash, channel.remote_static);
+    tx.send(handshake.hello(&ctx.identity)).await?;
+
+    // Fired by the heartbeat once the peer stops answering.
+    let (dead_tx, dead_rx) = oneshot::channel::<String>();
+    let mut dead_tx = Some(dead_tx);
+
+    let session = async {
+        let mut decoder = Decoder::new();
+        let auth_deadline = tokio::time::Instant::now() + AUTH_TIMEOUT;
+        loop {
+            let received = if handshake.authenticated {
+                reader.recv().await?
+            } else {
+                tokio::time::timeout_at(auth_deadline, reader.recv())
+                    .await
+                    .map_err(|_| format!("{} did not authenticate in time", addr))??
+            };
+            let Some(data) = received else {
+                return Ok::<_, Box<dyn std::error::Error + Send + Sync>>(());
+            };
+            decoder.push(&data);
+            while let Some(msg) = decoder.next_message().map_err(|e| format!("{} sent a bad message: {}", addr, e))? {
+                // Before handling the message, which may wait on the
+                // workspace, so a busy workspace doesn't look like silence.
+                if handshake.authenticated {
+                    let pong = match msg {
+                        Message::Pong { id } => Some(id),
+                        _ => None,
+                    };
+                    mark_seen(&ctx, &handshake, &tx, pong).await;
+                }
+
+                match msg {
+                    Message::Hello { version, ref capabilities, .. } => {
+                        let reply = answer_hello(&msg, &mut handshake, &ctx.identity)
+                            .map_err(|e| format!("handshake with {} failed: {}", addr, e))?;
+                        // Everything after the Hellos uses the encoding both
+                        // sides support, in both directions.
+                        let encoding = Encoding::negotiate(version, capabilities);
+                        decoder.encoding = encoding;
+                        encoding_tx.send_replace(encoding);
+                        tx.send(reply).await?;
+                    }
+                    Message::Auth { .. } => {
+                        verify_handshake(&msg, &mut handshake, addr, dialed, &tx, &ctx)
+                            .await
+                            .map_err(|e| format!("handshake with {} failed: {}", addr, e))?;
+                        if let (Some((peer_id, key)), Some(dead)) = (&handshake.claimed, dead_tx.take()) {
+                            tokio::spawn(heartbeat(ctx.clone(), peer_id.clone(), key.to_bytes(), tx.clone(), dead));
                         }
+                        send_sync(&ctx, &handshake, &tx, None).await;
                     }
+                    Message::Sync { list, data } => {
+                        if !handshake.authenticated {
+                            return Err(format!("{} sent changes before authenticating", addr).into());
+                        }
+                        send_sync(&ctx, &handshake, &tx, Some((&list, &data))).await;
+                    }
+                    Message::Ping { id } => {
+                        tx.send(Message::Pong { id }).await?;
+                    }
+                    Message::Pong { .. } => {}
+                }
+            }
+        }
+    };
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = tokio::select! {
+        result = session => result,
+        Ok(reason) = dead_rx => Err(format!("{} {}", addr, reason).into()),
+    };
+
+    // on disconnect, remove peer (if the entry is still this session's)
+    if let (true, Some((peer_id, _))) = (handshake.authenticated, &handshake.claimed) {
+        let mut peers = ctx.shared_peers.lock().await;
+        if peers.get(peer_id).is_some_and(|p| p.is_session(&tx)) {
+            peers.remove(peer_id);
+            println!("Unregistered peer {}", addr);
+        }
+    }
+    result
+}
+
+/// Notes that the peer on this connection is alive, and works out the round
+/// trip time if `pong` answers the ping we're waiting on.
+async fn mark_seen(ctx: &NetworkContext, handshake: &Handshake, tx: &mpsc::Sender<Message>, pong: Option<u64>) {
+    let Some((peer_id, _)) = &handshake.claimed else { return };
+    let mut peers = ctx.shared_peers.lock().await;
+    let Some(peer) = peers.get_mut(peer_id).filter(|peer| peer.is_session(tx)) else { return };
+
+    peer.last_seen = Instant::now();
+    if let (Some(id), Some((pending, sent))) = (pong, peer.pending_ping) {
+        if id == pending {
+            peer.rtt = Some(sent.elapsed());
+            peer.pending_ping = None;
+        }
+    }
+}
+
+/// Pings an authenticated peer every `PING_INTERVAL` and reports on `dead`
+/// once it has gone `MAX_MISSED_PONGS` intervals without a word. Until the
+/// peer first answers it gets the pairing timeout on top, since it may be
+/// waiting for its user to confirm us. Stops when the session ends, and
+/// ends the session if the peer is no longer registered to it (another
+/// session may have replaced it) or has been revoked (unless unknown peers
+/// are accepted anyway).
+async fn heartbeat(ctx: NetworkContext, peer_id: PeerId, public_key: [u8; 32], tx: mpsc::Sender<Message>, dead: oneshot::Sender<String>) {
+    let mut interval = tokio::time::interval(PING_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if dead.is_closed() {
+            return;
+        }
+
+        let revoked = {
+            let mut trust = ctx.trust.lock().await;
+            trust.refresh();
+            !trust.is_trusted(&public_key) && trust.effective_policy() != TrustPolicy::Accept
+        };
+        if revoked {
+            let _ = dead.send("is no longer trusted".to_string());
+            return;
+        }
+
+        let id = OsRng.next_u64();
+        let silent_for = {
+            let mut peers = ctx.shared_peers.lock().await;
+            let Some(peer) = peers.get_mut(&peer_id).filter(|peer| peer.is_session(&tx)) else {
+                let _ = dead.send("is no longer registered".to_string());
+                return;
+            };
+            let allowed = match peer.rtt {
+                Some(_) => PING_INTERVAL * MAX_MISSED_PONGS,
+                None => PING_INTERVAL * MAX_MISSED_PONGS + PAIRING_TIMEOUT,
+            };
+            if peer.last_seen.elapsed() > allowed {
+                Some(peer.last_seen.elapsed())
+            } else {
+                peer.pending_ping = Some((id, Instant::now()));
+                None
+            }
+        };
+        if let Some(silent_for) = silent_for {
+            let _ = dead.send(format!("stopped responding ({}s without a pong)", silent_for.as_secs()));
+            return;
+        }
+        if tx.send(Message::Ping { id }).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Applies `received` (a list key and sync message), if any, from the peer
+/// on this connection and answers it with the next message of the sync
+/// protocol. With nothing received, starts syncing every shared list.
+/// Changes to lists we don't share with the peer are ignored.
+///
+/// Changes that were new to us are relayed to every other peer sharing the
+/// list, so they cross the mesh without a direct connection. Each peer's
+/// sync state tracks which change hashes it already has, so only what it
+/// lacks is sent, and a change that comes back to us applies nothing new
+/// and goes no further.
+async fn send_sync(ctx: &NetworkContext, handshake: &Handshake, tx: &mpsc::Sender<Message>, received: Option<(&str, &[u8])>) {
+    let Some((peer_id, _)) = &handshake.claimed else { return };
+
+    let mut workspace = ctx.workspace.lock().await;
+    let mut peers = ctx.shared_peers.lock().await;
+    let Some(peer) = peers.get_mut(peer_id).filter(|peer| peer.is_session(tx)) else { return };
+
+    let Some((list, bytes)) = received else {
+        for key in workspace.list_keys() {
+            if let Some(crdt) = workspace.get_mut(&key) {
+                if !sync_peer(crdt, peer) {
+                    drop_stalled(&mut peers, std::slice::from_ref(peer_id));
+                    return;
                 }
             }
-            Err(_) => break,
         }
+        return;
+    };
+
+    if !peer.shared_lists.includes(list) {
+        return;
     }
+    let Some(crdt) = workspace.list_for_sync(list) else { return };
+    let state = peer.sync_states.entry(list.to_string()).or_default();
+    let applied = match crdt.receive_sync_message(state, bytes) {
+        Ok(applied) => applied,
+        Err(e) => {
+            eprintln!("Failed to apply changes from {}: {}", peer_id.id, e);
+            return;
+        }
+    };
+    let mut stalled = Vec::new();
+    if !sync_peer(crdt, peer) {
+        stalled.push(peer_id.clone());
+    }
+
+    if applied {
+        for other in peers.values_mut().filter(|other| other.peer_id != *peer_id) {
+            if !sync_peer(crdt, other) {
+                stalled.push(other.peer_id.clone());
+            }
+        }
+    }
+    drop_stalled(&mut peers, &stalled);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reconnect::KnownPeers;
+    use crate::storage::ScratchDir;
+    use crate::trust::TrustStore;
 
-    // cleanup
-    let mut peers = shared_peers.lock().await;
-    peers.retain(|_, p| p.address != addr);
-    println!("Unregistered peer {}", addr);
-}
\ No newline at end of file
+    const HANDSHAKE_HASH: &[u8] = b"noise handshake hash";
+
+    fn context(dir: &ScratchDir, identity: Identity) -> NetworkContext {
+        let mut trust = TrustStore::load(&dir.join("trusted_peers.json")).unwrap();
+        trust.policy_override = Some(TrustPolicy::Accept);
+        let workspace = Workspace::open(&dir.join("data"), &dir.join("default.automerge")).unwrap();
+        NetworkContext {
+            identity: Arc::new(identity),
+            shared_peers: Arc::new(Mutex::new(HashMap::new())),
+            workspace: Arc::new(Mutex::new(workspace)),
+            trust: Arc::new(Mutex::new(trust)),
+            pairing: Arc::new(Mutex::new(Vec::new())),
+            listen_addrs: Vec::new(),
+            connect_port: 0,
+            known_peers: Arc::new(Mutex::new(KnownPeers::default())),
+            links: Arc::new(Mutex::new(Default::default())),
+        }
+    }
+
+    /// Our side of a channel whose other end proved it holds `remote`.
+    fn handshake_with(remote: &Identity) -> Handshake {
+        let remote_static = VerifyingKey::from_bytes(&remote.public_key).unwrap().to_montgomery().to_bytes();
+        Handshake::new(HANDSHAKE_HASH.to_vec(), remote_static)
+    }
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:7878".parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn registers_a_peer_once_and_rejects_a_second_auth() {
+        let dir = ScratchDir::new("auth");
+        let (ours, theirs) = (Identity::generate(), Identity::generate());
+        let ctx = context(&dir, ours);
+        let mut handshake = handshake_with(&theirs);
+        let mut their_handshake = handshake_with(&ctx.identity);
+        let (tx, _rx) = mpsc::channel(1);
+
+        answer_hello(&their_handshake.hello(&theirs), &mut handshake, &ctx.identity).unwrap();
+        let auth = answer_hello(&handshake.hello(&ctx.identity), &mut their_handshake, &theirs).unwrap();
+        verify_handshake(&auth, &mut handshake, addr(), None, &tx, &ctx).await.unwrap();
+        assert!(handshake.authenticated);
+        ctx.shared_peers.lock().await.get_mut(&theirs.derive_peer_id()).unwrap().rtt = Some(Duration::from_millis(5));
+
+        let error = verify_handshake(&auth, &mut handshake, addr(), None, &tx, &ctx).await.unwrap_err();
+        assert_eq!(error, "duplicate Auth");
+        // The registered peer is left as it was.
+        assert!(ctx.shared_peers.lock().await[&theirs.derive_peer_id()].rtt.is_some());
+    }
+
+    #[test]
+    fn rejects_a_duplicate_hello() {
+        let (ours, theirs) = (Identity::generate(), Identity::generate());
+        let mut handshake = handshake_with(&theirs);
+        let hello = handshake_with(&ours).hello(&theirs);
+        answer_hello(&hello, &mut handshake, &ours).unwrap();
+        assert_eq!(answer_hello(&hello, &mut handshake, &ours).unwrap_err(), "duplicate Hello");
+    }
+
+    #[test]
+    fn rejects_a_peer_id_not_derived_from_the_key() {
+        let (ours, theirs) = (Identity::generate(), Identity::generate());
+        let mut handshake = handshake_with(&theirs);
+        let mut hello = handshake_with(&ours).hello(&theirs);
+        if let Message::Hello { peer_id, .. } = &mut hello {
+            *peer_id = ours.derive_peer_id().id;
+        }
+        let error = answer_hello(&hello, &mut handshake, &ours).unwrap_err();
+        assert!(error.contains("does not match its public key"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_a_key_other_than_the_channels() {
+        let (ours, theirs, intruder) = (Identity::generate(), Identity::generate(), Identity::generate());
+        let mut handshake = handshake_with(&theirs);
+        let hello = handshake_with(&ours).hello(&intruder);
+        let error = answer_hello(&hello, &mut handshake, &ours).unwrap_err();
+        assert_eq!(error, "public key does not match the encrypted channel");
+    }
+
+    #[tokio::test]
+    async fn rejects_a_bad_signature() {
+        let dir = ScratchDir::new("signature");
+        let (ours, theirs) = (Identity::generate(), Identity::generate());
+        let ctx = context(&dir, ours);
+        let mut handshake = handshake_with(&theirs);
+        let (tx, _rx) = mpsc::channel(1);
+        answer_hello(&handshake_with(&ctx.identity).hello(&theirs), &mut handshake, &ctx.identity).unwrap();
+
+        // Signed over someone else's nonce.
+        let signature = theirs.sign(&auth_payload(HANDSHAKE_HASH, &[0; NONCE_LENGTH], &theirs.derive_peer_id()));
+        let auth = Message::Auth { signature: general_purpose::STANDARD.encode(signature.to_bytes()) };
+        let error = verify_handshake(&auth, &mut handshake, addr(), None, &tx, &ctx).await.unwrap_err();
+        assert!(error.contains("failed the challenge"), "{}", error);
+        assert!(!handshake.authenticated);
+        assert!(ctx.shared_peers.lock().await.is_empty());
+    }
+}
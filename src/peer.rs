@@ -2,7 +2,9 @@ use std::net::SocketAddr;
 use tokio::sync::mpsc::Sender;
 use crate::network::Message;
 use std::collections::HashMap;
+use crate::workspace::ShareScope;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
@@ -13,8 +15,29 @@ pub struct PeerId {
 pub struct Peer {
     pub peer_id: PeerId,
     pub address: SocketAddr,
+    /// Whether we dialed this connection.
+    pub outgoing: bool,
     pub public_key: [u8; 32],
     pub sender: Option<Sender<Message>>,
+    /// What we know about this peer's copy of each list, per the Automerge
+    /// sync protocol, keyed by list key. Starts empty on every connection.
+    pub sync_states: HashMap<String, automerge::sync::State>,
+    /// The lists we sync with this peer.
+    pub shared_lists: ShareScope,
+    /// When anything last arrived from this peer.
+    pub last_seen: Instant,
+    /// Round trip time of the last answered ping.
+    pub rtt: Option<Duration>,
+    /// The ping waiting for a pong, and when it was sent.
+    pub pending_ping: Option<(u64, Instant)>,
+}
+
+impl Peer {
+    /// Whether this entry belongs to the session sending on `tx`. A newer
+    /// session for the same peer may have taken the entry over.
+    pub fn is_session(&self, tx: &Sender<Message>) -> bool {
+        self.sender.as_ref().is_some_and(|sender| sender.same_channel(tx))
+    }
 }
 
 pub type SharedPeers = Arc<Mutex<HashMap<PeerId, Peer>>>;
\ No newline at end of file
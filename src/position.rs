@@ -0,0 +1,50 @@
+/// Digits of the ordering keys, in ASCII order so keys compare correctly as
+/// plain strings.
+const DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+const BASE: usize = DIGITS.len();
+
+fn digit_value(c: u8) -> usize {
+    DIGITS.iter().position(|&d| d == c).unwrap_or(0)
+}
+
+/// Returns a key that sorts strictly between `before` and `after`, treating
+/// `None` as the start or end of the list.
+///
+/// Keys are fractional indices: digits after an implied "0.", so there is
+/// always room between two distinct keys and a task can be moved by
+/// rewriting only its own key. Two peers moving different tasks into the
+/// same gap may pick the same key; callers break the tie deterministically.
+pub fn key_between(before: Option<&str>, after: Option<&str>) -> String {
+    let before = before.unwrap_or("").as_bytes();
+    match after {
+        Some(after) if after.as_bytes() > before => midpoint(before, Some(after.as_bytes())),
+        _ => midpoint(before, None),
+    }
+}
+
+fn midpoint(a: &[u8], b: Option<&[u8]>) -> String {
+    if let Some(b) = b {
+        // Copy the shared prefix (reading past the end of `a` as zeros).
+        let common = b
+            .iter()
+            .enumerate()
+            .take_while(|&(i, &c)| a.get(i).copied().unwrap_or(DIGITS[0]) == c)
+            .count();
+        if common > 0 {
+            let rest = midpoint(a.get(common..).unwrap_or(&[]), Some(&b[common..]));
+            return format!("{}{}", String::from_utf8_lossy(&b[..common]), rest);
+        }
+    }
+
+    let low = a.first().map_or(0, |&c| digit_value(c));
+    let high = b.and_then(|b| b.first()).map_or(BASE, |&c| digit_value(c));
+    if high - low > 1 {
+        return (DIGITS[(low + high) / 2] as char).to_string();
+    }
+
+    match b {
+        // `b` has more digits, so its first digit alone is already above `a`.
+        Some(b) if b.len() > 1 => (b[0] as char).to_string(),
+        _ => format!("{}{}", DIGITS[low] as char, midpoint(a.get(1..).unwrap_or(&[]), None)),
+    }
+}
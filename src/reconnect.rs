@@ -0,0 +1,157 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use crate::network::{connect_to_peer, NetworkContext};
+use crate::peer::PeerId;
+
+const KNOWN_PEERS_FILE_NAME: &str = "known_peers.json";
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// Failed attempts in a row before a peer is given up on for this session.
+const MAX_ATTEMPTS: u32 = 10;
+
+/// Addresses of the peers we have dialed successfully, keyed by peer id, so
+/// they can be reconnected to on the next start.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct KnownPeers {
+    #[serde(default)]
+    pub peers: BTreeMap<String, String>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+pub type SharedKnownPeers = Arc<Mutex<KnownPeers>>;
+
+impl KnownPeers {
+    pub fn path_in(data_dir: &Path) -> PathBuf {
+        data_dir.join(KNOWN_PEERS_FILE_NAME)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut known = match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid known peers file: {}", e)))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => KnownPeers::default(),
+            Err(e) => return Err(e),
+        };
+        known.path = path.to_path_buf();
+        Ok(known)
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let contents = serde_json::to_string_pretty(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(&self.path, contents)
+    }
+
+    /// Records the address `peer_id` was reached at, saving only if it changed.
+    pub fn remember(&mut self, peer_id: &PeerId, address: &str) -> io::Result<()> {
+        if self.peers.get(&peer_id.id).map(String::as_str) == Some(address) {
+            return Ok(());
+        }
+        self.peers.insert(peer_id.id.clone(), address.to_string());
+        self.save()
+    }
+}
+
+/// Where an outgoing connection stands.
+#[derive(Clone, Debug)]
+pub enum LinkState {
+    Connecting,
+    Connected(PeerId),
+    Retrying { attempt: u32, next_try: Instant },
+    GaveUp(String),
+}
+
+impl fmt::Display for LinkState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkState::Connecting => f.write_str("connecting"),
+            LinkState::Connected(peer_id) => write!(f, "connected to {}", peer_id.id),
+            LinkState::Retrying { attempt, next_try } => write!(
+                f,
+                "retrying in {:.1}s (attempt {} of {})",
+                next_try.saturating_duration_since(Instant::now()).as_secs_f64(),
+                attempt + 1,
+                MAX_ATTEMPTS
+            ),
+            LinkState::GaveUp(reason) => write!(f, "given up: {}", reason),
+        }
+    }
+}
+
+/// Outgoing connections by the address they were dialed at.
+pub type LinkStates = Arc<Mutex<BTreeMap<String, LinkState>>>;
+
+/// How long to wait before attempt number `attempt` (counting from 1):
+/// doubling from `INITIAL_BACKOFF` up to `MAX_BACKOFF`, with a random half of
+/// it taken off so peers that dropped together don't all retry together.
+fn backoff(attempt: u32) -> Duration {
+    let delay = INITIAL_BACKOFF.saturating_mul(1 << attempt.saturating_sub(1).min(16)).min(MAX_BACKOFF);
+    delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+}
+
+/// Connects to `target` and keeps reconnecting whenever the connection
+/// drops or can't be made, backing off between attempts, until
+/// `MAX_ATTEMPTS` attempts in a row have failed. Does nothing if `target` is
+/// already being looked after.
+pub async fn maintain(target: String, ctx: NetworkContext) {
+    {
+        let mut links = ctx.links.lock().await;
+        if links.get(&target).is_some_and(|state| !matches!(state, LinkState::GaveUp(_))) {
+            return;
+        }
+        links.insert(target.clone(), LinkState::Connecting);
+    }
+
+    let mut failures = 0;
+    let mut last_peer: Option<PeerId> = None;
+    loop {
+        let result = connect_to_peer(target.clone(), ctx.clone()).await;
+
+        let mut links = ctx.links.lock().await;
+        if let Some(LinkState::Connected(peer_id)) = links.get(&target) {
+            // The session got through the handshake, so start counting afresh.
+            last_peer = Some(peer_id.clone());
+            failures = 0;
+        }
+        if let Err(e) = &result {
+            failures += 1;
+            if failures >= MAX_ATTEMPTS {
+                println!("Giving up on {} after {} attempts: {}", target, failures, e);
+                links.insert(target.clone(), LinkState::GaveUp(e.to_string()));
+                return;
+            }
+        }
+
+        let delay = backoff(failures.max(1));
+        links.insert(target.clone(), LinkState::Retrying { attempt: failures, next_try: Instant::now() + delay });
+        drop(links);
+        match &result {
+            Ok(()) => println!("Reconnecting to {} in {:.1}s", target, delay.as_secs_f64()),
+            Err(e) => println!("Failed to connect to peer {}: {} (retrying in {:.1}s)", target, e, delay.as_secs_f64()),
+        }
+        tokio::time::sleep(delay).await;
+
+        // The peer may have dialed us in the meantime; no need for a second
+        // session while that one lasts.
+        while let Some(peer_id) = &last_peer {
+            if !ctx.shared_peers.lock().await.contains_key(peer_id) {
+                break;
+            }
+            ctx.links.lock().await.insert(target.clone(), LinkState::Connected(peer_id.clone()));
+            tokio::time::sleep(MAX_BACKOFF / 10).await;
+        }
+        ctx.links.lock().await.insert(target.clone(), LinkState::Connecting);
+    }
+}
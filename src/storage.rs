@@ -0,0 +1,279 @@
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use sha2::{Digest, Sha256};
+
+/// A change log starts with a random generation number. Compaction starts a
+/// new log with a new one, so readers can tell a new log from one that grew.
+const LOG_HEADER: u64 = 8;
+/// Each record is its length (`u32`, big-endian), the first bytes of its
+/// SHA-256 and the record itself.
+const RECORD_LENGTH: usize = 4;
+const RECORD_CHECKSUM: usize = 8;
+
+/// `path` with `suffix` appended to its file name.
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Holds an exclusive lock on a file shared by every process using the same
+/// data, released when dropped.
+pub struct FileLock {
+    _file: File,
+}
+
+/// Waits for the exclusive lock guarding `path`. The lock is taken on a
+/// `.lock` file next to it, since `path` itself is replaced on every write.
+pub fn lock(path: &Path) -> io::Result<FileLock> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(sibling(path, ".lock"))?;
+    file.lock()?;
+    Ok(FileLock { _file: file })
+}
+
+/// The contents of `path`, or `None` if it doesn't exist yet.
+pub fn read_if_exists(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Replaces `path` with `contents` so that readers see either the old file
+/// or the new one, never a partial write.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let temp = sibling(path, ".tmp");
+    let result = (|| {
+        let mut file = File::create(&temp)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(&temp, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+        return result;
+    }
+    sync_parent(path)
+}
+
+/// Makes a rename or newly created file in `path`'s directory durable.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        Some(parent) => File::open(parent)?.sync_all(),
+        None => File::open(".")?.sync_all(),
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// The change log kept next to the snapshot at `path`.
+pub fn log_path(path: &Path) -> PathBuf {
+    sibling(path, ".log")
+}
+
+/// How far a change log has been read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LogPosition {
+    pub generation: u64,
+    pub offset: u64,
+}
+
+pub struct LogRecords {
+    pub generation: u64,
+    pub records: Vec<Vec<u8>>,
+    /// Where the last complete record ends. Anything after it is a write
+    /// that was cut short, which the next append overwrites.
+    pub end: u64,
+}
+
+impl LogRecords {
+    pub fn position(&self) -> LogPosition {
+        LogPosition { generation: self.generation, offset: self.end }
+    }
+}
+
+fn checksum(record: &[u8]) -> [u8; RECORD_CHECKSUM] {
+    let digest = Sha256::digest(record);
+    digest[..RECORD_CHECKSUM].try_into().expect("digest is longer than the checksum")
+}
+
+/// Reads the change log at `path`: the records after `from` if the log is
+/// still of the same generation, all of them otherwise. Reading stops at
+/// the first record that is incomplete or fails its checksum. Returns
+/// `None` if there is no log.
+pub fn read_log(path: &Path, from: Option<LogPosition>) -> io::Result<Option<LogRecords>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut header = [0u8; LOG_HEADER as usize];
+    match file.read_exact(&mut header) {
+        Ok(()) => {}
+        // Cut short while being created; as good as no log.
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let generation = u64::from_be_bytes(header);
+    let start = match from {
+        Some(position) if position.generation == generation => position.offset,
+        _ => LOG_HEADER,
+    };
+    file.seek(SeekFrom::Start(start))?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+
+    let mut records = Vec::new();
+    let mut rest = &bytes[..];
+    let mut end = start;
+    while rest.len() >= RECORD_LENGTH + RECORD_CHECKSUM {
+        let (length, tail) = rest.split_at(RECORD_LENGTH);
+        let (sum, tail) = tail.split_at(RECORD_CHECKSUM);
+        let length = u32::from_be_bytes(length.try_into().expect("length is four bytes")) as usize;
+        let Some(record) = tail.get(..length) else { break };
+        if checksum(record) != sum {
+            break;
+        }
+        records.push(record.to_vec());
+        rest = &tail[length..];
+        end += (RECORD_LENGTH + RECORD_CHECKSUM + length) as u64;
+    }
+    Ok(Some(LogRecords { generation, records, end }))
+}
+
+/// Appends `record` to the log at `path` at `position`, replacing whatever
+/// follows it, or starts a new log holding it if there is none.
+pub fn append_record(path: &Path, position: Option<LogPosition>, record: &[u8]) -> io::Result<LogPosition> {
+    let length = u32::try_from(record.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "log record too large"))?;
+    let mut frame = Vec::with_capacity(RECORD_LENGTH + RECORD_CHECKSUM + record.len());
+    frame.extend_from_slice(&length.to_be_bytes());
+    frame.extend_from_slice(&checksum(record));
+    frame.extend_from_slice(record);
+
+    let Some(position) = position else {
+        let generation = rand::random::<u64>();
+        let mut contents = generation.to_be_bytes().to_vec();
+        contents.extend_from_slice(&frame);
+        write_atomic(path, &contents)?;
+        return Ok(LogPosition { generation, offset: contents.len() as u64 });
+    };
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(position.offset)?;
+    file.seek(SeekFrom::Start(position.offset))?;
+    file.write_all(&frame)?;
+    file.sync_data()?;
+    Ok(LogPosition { generation: position.generation, offset: position.offset + frame.len() as u64 })
+}
+
+/// Replaces the log at `path` with an empty one of a new generation.
+pub fn new_log(path: &Path) -> io::Result<LogPosition> {
+    let generation = rand::random::<u64>();
+    write_atomic(path, &generation.to_be_bytes())?;
+    Ok(LogPosition { generation, offset: LOG_HEADER })
+}
+
+/// A fresh, empty directory for one test, removed when dropped.
+#[cfg(test)]
+pub struct ScratchDir(PathBuf);
+
+#[cfg(test)]
+impl ScratchDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("rustytasks-{}-{:016x}", name, rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        ScratchDir(dir)
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+#[cfg(test)]
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(log: &Path, from: Option<LogPosition>) -> Vec<Vec<u8>> {
+        read_log(log, from).unwrap().unwrap().records
+    }
+
+    #[test]
+    fn torn_final_record_is_skipped() {
+        let dir = ScratchDir::new("torn");
+        let log = dir.join("list.log");
+        let first = append_record(&log, None, b"first").unwrap();
+        let second = append_record(&log, Some(first), b"second").unwrap();
+        // The length prefix made it to disk, but not all of the body.
+        OpenOptions::new().write(true).open(&log).unwrap().set_len(second.offset - 3).unwrap();
+
+        let read = read_log(&log, None).unwrap().unwrap();
+        assert_eq!(read.records, vec![b"first".to_vec()]);
+        assert_eq!(read.position(), first);
+    }
+
+    #[test]
+    fn record_failing_its_checksum_ends_the_log() {
+        let dir = ScratchDir::new("checksum");
+        let log = dir.join("list.log");
+        let first = append_record(&log, None, b"first").unwrap();
+        let second = append_record(&log, Some(first), b"second").unwrap();
+        append_record(&log, Some(second), b"third").unwrap();
+        let mut bytes = fs::read(&log).unwrap();
+        bytes[second.offset as usize - 1] ^= 0xff;
+        fs::write(&log, bytes).unwrap();
+
+        let read = read_log(&log, None).unwrap().unwrap();
+        assert_eq!(read.records, vec![b"first".to_vec()]);
+        assert_eq!(read.position(), first);
+    }
+
+    #[test]
+    fn append_replaces_a_torn_tail() {
+        let dir = ScratchDir::new("append");
+        let log = dir.join("list.log");
+        let first = append_record(&log, None, b"first").unwrap();
+        let second = append_record(&log, Some(first), b"second").unwrap();
+        OpenOptions::new().write(true).open(&log).unwrap().set_len(second.offset - 3).unwrap();
+
+        let position = read_log(&log, None).unwrap().unwrap().position();
+        let third = append_record(&log, Some(position), b"third").unwrap();
+        assert_eq!(records(&log, None), vec![b"first".to_vec(), b"third".to_vec()]);
+        assert_eq!(fs::metadata(&log).unwrap().len(), third.offset);
+        assert!(records(&log, Some(third)).is_empty());
+    }
+
+    #[test]
+    fn new_generation_is_read_from_the_start() {
+        let dir = ScratchDir::new("generation");
+        let log = dir.join("list.log");
+        let old = append_record(&log, None, b"before compaction").unwrap();
+        let fresh = new_log(&log).unwrap();
+        assert_ne!(fresh.generation, old.generation);
+        let after = append_record(&log, Some(fresh), b"after compaction").unwrap();
+
+        // A position in the old log says nothing about the new one.
+        let read = read_log(&log, Some(old)).unwrap().unwrap();
+        assert_eq!(read.generation, fresh.generation);
+        assert_eq!(read.records, vec![b"after compaction".to_vec()]);
+        assert_eq!(read.position(), after);
+    }
+}
@@ -1,21 +1,54 @@
-use automerge::ChangeHash;
+use std::collections::HashMap;
+use tokio::sync::mpsc::error::TrySendError;
+use crate::crdt::CrdtToDoList;
+use crate::network::Message;
+use crate::peer::{Peer, PeerId, SharedPeers};
 
-pub struct SyncState {
-    received_changes: Vec<ChangeHash>,
+/// Sends every connected peer whatever it is still missing, according to
+/// that peer's own Automerge sync state. Peers that are up to date, or that
+/// still have a message of ours in flight, are skipped.
+pub async fn sync_peers(crdt: &mut CrdtToDoList, shared_peers: &SharedPeers) {
+    let mut peers = shared_peers.lock().await;
+    let stalled: Vec<PeerId> = peers
+        .values_mut()
+        .filter_map(|peer| (!sync_peer(crdt, peer)).then(|| peer.peer_id.clone()))
+        .collect();
+    drop_stalled(&mut peers, &stalled);
 }
 
-impl SyncState {
-    pub fn new() -> Self {
-        SyncState {
-            received_changes: Vec::new(),
-        }
+/// Runs one round of the sync protocol towards a single peer, if the list
+/// is shared with it. Never waits on the connection, since callers hold the
+/// workspace and peer locks: returns `false` if the peer's outgoing queue is
+/// full or closed, in which case it should be dropped with `drop_stalled`.
+pub fn sync_peer(crdt: &mut CrdtToDoList, peer: &mut Peer) -> bool {
+    if !peer.shared_lists.includes(&crdt.list_key) {
+        return true;
     }
+    let state = peer.sync_states.entry(crdt.list_key.clone()).or_default();
+    let Some(data) = crdt.generate_sync_message(state) else {
+        return true;
+    };
 
-    pub fn add_received_change(&mut self, change_hash: ChangeHash) {
-        self.received_changes.push(change_hash);
+    let Some(sender) = &peer.sender else {
+        eprintln!("No sender channel found for peer: {}", peer.peer_id.id);
+        return true;
+    };
+    match sender.try_send(Message::Sync { list: crdt.list_key.clone(), data }) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            eprintln!("Peer {} is not keeping up with changes", peer.peer_id.id);
+            false
+        }
+        Err(TrySendError::Closed(_)) => false,
     }
+}
 
-    pub fn get_have_deps(&self) -> Vec<ChangeHash> {
-        self.received_changes.clone()
+/// Unregisters peers whose connection stopped taking messages. The session's
+/// heartbeat notices the peer is gone and closes the connection.
+pub fn drop_stalled(peers: &mut HashMap<PeerId, Peer>, stalled: &[PeerId]) {
+    for peer_id in stalled {
+        if peers.remove(peer_id).is_some() {
+            println!("Dropping peer {}", peer_id.id);
+        }
     }
 }
@@ -1,54 +1,239 @@
-use std::io::{stdin, stdout, BufWriter, Write};
-use crate::{crdt::CrdtToDoList, display::show_welcome_screen};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::{stdout, BufWriter, Write};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+use clap::ValueEnum;
+use colored::Colorize;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use crate::{crdt::CrdtToDoList, display::press_enter_to_continue};
 
-#[derive(Clone, Debug)]
+/// Number of id characters shown in listings. Any unique prefix works when
+/// addressing a task, so this is only a display choice.
+const SHORT_ID_LENGTH: usize = 8;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Task {
+    /// Stable identifier, the same on every replica no matter where the task
+    /// sits in the list.
+    pub id: String,
     pub name: String,
+    /// Free-form longer description.
+    pub notes: String,
     pub status: bool,
+    pub due: Option<DateTime<Local>>,
+    pub priority: Priority,
+    pub tags: BTreeSet<String>,
+    /// Nesting level; 0 for top-level tasks.
+    pub depth: usize,
+    /// Id of the task this is a subtask of.
+    pub parent: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+    Urgent,
+}
+
+impl Priority {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+            Priority::Urgent => "urgent",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Priority::from_str(value.trim(), true).ok()
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SortKey {
+    /// Earliest due date first; tasks without one last
+    Due,
+    /// Most urgent first
+    Priority,
+    Name,
+    /// Open tasks first
+    Status,
+}
+
+/// How `list_tasks` should filter and order the tasks it prints.
+#[derive(Clone, Debug, Default)]
+pub struct ListOptions {
+    pub sort: Option<SortKey>,
+    /// Only show tasks at this priority or above.
+    pub min_priority: Option<Priority>,
+    pub overdue_only: bool,
+    /// Only show tasks carrying all of these tags.
+    pub tags: Vec<String>,
+}
+
+/// Canonical form of a tag: trimmed, lowercase, without a leading `#`.
+pub fn normalize_tag(tag: &str) -> String {
+    tag.trim().trim_start_matches('#').to_lowercase()
+}
+
+/// Time used for due dates given without one: the end of that day.
+fn end_of_day() -> NaiveTime {
+    NaiveTime::from_hms_opt(23, 59, 0).unwrap()
+}
+
+/// Parses a due date in local time: `YYYY-MM-DD`, `YYYY-MM-DD HH:MM`,
+/// `today` or `tomorrow`. Dates without a time are due at the end of the day.
+pub fn parse_due(input: &str) -> Result<DateTime<Local>, String> {
+    let input = input.trim();
+    let naive = match input.to_lowercase().as_str() {
+        "today" => Local::now().date_naive().and_time(end_of_day()),
+        "tomorrow" => (Local::now().date_naive() + chrono::Days::new(1)).and_time(end_of_day()),
+        _ => NaiveDateTime::parse_from_str(input, "%Y-%m-%d %H:%M")
+            .or_else(|_| NaiveDateTime::parse_from_str(input, "%Y-%m-%dT%H:%M"))
+            .or_else(|_| NaiveDate::parse_from_str(input, "%Y-%m-%d").map(|d| d.and_time(end_of_day())))
+            .map_err(|_| format!("Invalid due date '{}'; use YYYY-MM-DD or YYYY-MM-DD HH:MM", input))?,
+    };
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .ok_or_else(|| format!("'{}' does not exist in the local time zone", input))
+}
+
+#[derive(Debug)]
+pub enum TaskLookupError {
+    NotFound(String),
+    Ambiguous(String, usize),
+}
+
+impl fmt::Display for TaskLookupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TaskLookupError::NotFound(query) => write!(f, "No task matches ID '{}'", query),
+            TaskLookupError::Ambiguous(query, count) => {
+                write!(f, "ID '{}' matches {} tasks; use a longer prefix", query, count)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TaskLookupError {}
+
+pub fn new_task_id() -> String {
+    let mut bytes = [0u8; 8];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
 }
 
 impl Task {
     pub fn add_task(todo: &mut Vec<Task>, name: String) {
         let task = Task {
+            id: new_task_id(),
             name,
+            notes: String::new(),
             status: false,
+            due: None,
+            priority: Priority::Normal,
+            tags: BTreeSet::new(),
+            depth: 0,
+            parent: None,
         };
         todo.push(task);
     }
 
-    pub fn remove_task(todo: &mut Vec<Task>, index: usize) {
-        if index < todo.len() {
-            todo.remove(index);
-        } else {
-            println!("Invalid index: {}", index);
+    /// Finds the position of the task whose id is `query` or starts with it.
+    pub fn resolve<'a>(todo: impl IntoIterator<Item = &'a Task>, query: &str) -> Result<usize, TaskLookupError> {
+        let query = query.trim();
+        let mut found = None;
+        let mut count = 0;
+        for (index, task) in todo.into_iter().enumerate() {
+            if task.id == query {
+                return Ok(index);
+            }
+            if !query.is_empty() && task.id.starts_with(query) {
+                found = Some(index);
+                count += 1;
+            }
+        }
+        match (found, count) {
+            (Some(index), 1) => Ok(index),
+            (None, _) => Err(TaskLookupError::NotFound(query.to_string())),
+            (Some(_), count) => Err(TaskLookupError::Ambiguous(query.to_string(), count)),
         }
     }
 
-    pub fn mark_done(todo: &mut Vec<Task>, index: usize) {
-        if let Some(task) = todo.get_mut(index) {
-            task.status = true;
-        } else {
-            println!("Invalid index: {}", index);
-        }
+    /// The first `SHORT_ID_LENGTH` characters of the id. Ids come from
+    /// peers, so this must not assume they are ASCII.
+    pub fn short_id(&self) -> &str {
+        self.id.char_indices().nth(SHORT_ID_LENGTH).map_or(&self.id[..], |(i, _)| &self.id[..i])
     }
 
-    pub fn list_tasks(todo: &Vec<Task>) {
+    pub fn list_tasks(todo: &[Task], options: &ListOptions) {
         let stdout = stdout();
         let mut writer = BufWriter::new(stdout.lock());
+        let now = Local::now();
 
-        writeln!(writer, "\n\n{:<5} {:<30} {}", "ID", "Name", "Status").unwrap();
-        writeln!(writer, "{}", "-".repeat(50)).unwrap();
+        let mut shown: Vec<(usize, &Task)> = todo
+            .iter()
+            .enumerate()
+            .filter(|(_, task)| options.min_priority.is_none_or(|p| task.priority >= p))
+            .filter(|(_, task)| !options.overdue_only || task.is_overdue(now))
+            .filter(|(_, task)| options.tags.iter().all(|tag| task.tags.contains(&normalize_tag(tag))))
+            .collect();
+        match options.sort {
+            Some(SortKey::Due) => shown.sort_by_key(|(_, task)| (task.due.is_none(), task.due)),
+            Some(SortKey::Priority) => shown.sort_by_key(|(_, task)| std::cmp::Reverse(task.priority)),
+            Some(SortKey::Name) => shown.sort_by_key(|(_, task)| task.name.to_lowercase()),
+            Some(SortKey::Status) => shown.sort_by_key(|(_, task)| task.status),
+            None => {}
+        }
 
-        for (index, task) in todo.iter().enumerate() {
-            writeln!(writer, "{:<5} {:<30} {}", index, task.name.trim_end(), task.status_string()).unwrap();
+        writeln!(writer, "\n\n{:<5} {:<10} {:<30} {:<8} {:<17} Status", "#", "ID", "Name", "Priority", "Due").unwrap();
+        writeln!(writer, "{}", "-".repeat(85)).unwrap();
+
+        for (index, task) in shown {
+            let due = task.due.map(format_due).unwrap_or_default();
+            let due = if task.is_overdue(now) {
+                format!("{:<17}", due).red().bold().to_string()
+            } else {
+                format!("{:<17}", due)
+            };
+            let tags: Vec<String> = task.tags.iter().map(|tag| format!("#{}", tag)).collect();
+            // Indentation only makes sense while tasks are in tree order.
+            let indent = if options.sort.is_none() { "  ".repeat(task.depth) } else { String::new() };
+            let name = if indent.is_empty() || task.depth == 0 {
+                task.name.trim_end().to_string()
+            } else {
+                format!("{}↳ {}", &indent[2..], task.name.trim_end())
+            };
+            writeln!(
+                writer,
+                "{:<5} {:<10} {:<30} {:<8} {} {:<11} {}",
+                index, task.short_id(), name, task.priority, due, task.status_string(), tags.join(" ").cyan()
+            ).unwrap();
+            for line in task.notes.lines() {
+                writeln!(writer, "{:<16} {}{}", "", indent, line.dimmed()).unwrap();
+            }
         }
 
         writer.flush().unwrap();
-        print!("\n\n{}", "Press Enter to continue...");
-        let mut input = String::new();
-        let _ = std::io::stdout().flush();
-        stdin().read_line(&mut input).expect("Failed to read line");
-        show_welcome_screen();
+        println!("\n");
+        press_enter_to_continue();
+    }
+
+    pub fn is_overdue(&self, now: DateTime<Local>) -> bool {
+        !self.status && self.due.is_some_and(|due| due < now)
     }
 
     fn status_string(&self) -> &'static str {
@@ -60,9 +245,35 @@ impl Task {
     }
 }
 
+fn format_due(due: DateTime<Local>) -> String {
+    if due.time() == end_of_day() {
+        due.format("%Y-%m-%d").to_string()
+    } else {
+        due.format("%Y-%m-%d %H:%M").to_string()
+    }
+}
+
 pub fn update_local_list_from_crdt(crdt: &CrdtToDoList, todo: &mut Vec<Task>) {
     todo.clear();
     for entry in &crdt.task_entries {
         todo.push(entry.task.clone());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_id_handles_non_ascii_ids() {
+        let mut todo = Vec::new();
+        Task::add_task(&mut todo, "task".to_string());
+        let task = &mut todo[0];
+        assert_eq!(task.short_id().len(), SHORT_ID_LENGTH);
+
+        task.id = "ééééééééé".to_string();
+        assert_eq!(task.short_id(), "éééééééé");
+        task.id = "abc".to_string();
+        assert_eq!(task.short_id(), "abc");
+    }
+}
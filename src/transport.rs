@@ -0,0 +1,140 @@
+use std::io;
+use std::sync::Arc;
+use snow::{Builder, HandshakeState, StatelessTransportState};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use crate::identity::Identity;
+
+/// Mutual authentication with static keys exchanged inside the handshake.
+/// The static keys are the X25519 forms of each peer's ed25519 identity.
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_SHA256";
+const MAX_NOISE_MESSAGE: usize = 65535;
+const TAG_LENGTH: usize = 16;
+const MAX_PLAINTEXT: usize = MAX_NOISE_MESSAGE - TAG_LENGTH;
+
+/// An encrypted, authenticated connection. Every frame on the wire is a
+/// big-endian `u16` length followed by one Noise transport message.
+pub struct SecureChannel {
+    pub reader: SecureReader,
+    pub writer: SecureWriter,
+    /// The peer's X25519 static key, proven during the Noise handshake.
+    pub remote_static: [u8; 32],
+    /// Unique per session; signed during the application handshake to bind
+    /// the peer identity to this channel.
+    pub handshake_hash: Vec<u8>,
+}
+
+pub struct SecureReader {
+    inner: OwnedReadHalf,
+    transport: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+pub struct SecureWriter {
+    inner: OwnedWriteHalf,
+    transport: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+fn noise_error(e: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("noise: {}", e))
+}
+
+async fn write_frame(writer: &mut OwnedWriteHalf, frame: &[u8]) -> io::Result<()> {
+    writer.write_all(&(frame.len() as u16).to_be_bytes()).await?;
+    writer.write_all(frame).await?;
+    writer.flush().await
+}
+
+/// Reads one frame, or `None` if the connection was closed cleanly between
+/// frames.
+async fn read_frame(reader: &mut OwnedReadHalf) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 2];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut frame = vec![0u8; u16::from_be_bytes(len) as usize];
+    reader.read_exact(&mut frame).await?;
+    Ok(Some(frame))
+}
+
+/// Runs the Noise XX handshake over `stream`. `initiator` is true for the
+/// side that opened the connection.
+pub async fn handshake(stream: TcpStream, identity: &Identity, initiator: bool) -> io::Result<SecureChannel> {
+    let static_key = identity.signing_key().to_scalar_bytes();
+    let builder = Builder::new(NOISE_PARAMS.parse().map_err(noise_error)?).local_private_key(&static_key);
+    let mut noise = if initiator {
+        builder.build_initiator()
+    } else {
+        builder.build_responder()
+    }
+    .map_err(noise_error)?;
+
+    let (mut reader, mut writer) = stream.into_split();
+    let mut buf = vec![0u8; MAX_NOISE_MESSAGE];
+
+    // XX is three messages: -> e, <- e ee s es, -> s se
+    let mut our_turn = initiator;
+    while !noise.is_handshake_finished() {
+        if our_turn {
+            let len = noise.write_message(&[], &mut buf).map_err(noise_error)?;
+            write_frame(&mut writer, &buf[..len]).await?;
+        } else {
+            let frame = read_frame(&mut reader)
+                .await?
+                .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed during handshake"))?;
+            noise.read_message(&frame, &mut buf).map_err(noise_error)?;
+        }
+        our_turn = !our_turn;
+    }
+
+    into_channel(noise, reader, writer)
+}
+
+fn into_channel(noise: HandshakeState, reader: OwnedReadHalf, writer: OwnedWriteHalf) -> io::Result<SecureChannel> {
+    let remote_static: [u8; 32] = noise
+        .get_remote_static()
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "peer sent no static key"))?;
+    let handshake_hash = noise.get_handshake_hash().to_vec();
+    let transport = Arc::new(noise.into_stateless_transport_mode().map_err(noise_error)?);
+
+    Ok(SecureChannel {
+        reader: SecureReader { inner: reader, transport: transport.clone(), nonce: 0 },
+        writer: SecureWriter { inner: writer, transport, nonce: 0 },
+        remote_static,
+        handshake_hash,
+    })
+}
+
+impl SecureWriter {
+    /// Encrypts and sends `data`, split across as many frames as needed.
+    pub async fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut buf = vec![0u8; MAX_NOISE_MESSAGE];
+        for chunk in data.chunks(MAX_PLAINTEXT) {
+            let len = self.transport.write_message(self.nonce, chunk, &mut buf).map_err(noise_error)?;
+            self.nonce += 1;
+            write_frame(&mut self.inner, &buf[..len]).await?;
+        }
+        Ok(())
+    }
+}
+
+impl SecureReader {
+    /// Receives and decrypts the next frame. Returns `None` once the peer
+    /// has closed the connection. A frame that fails to decrypt is an error,
+    /// since it means the stream was tampered with.
+    pub async fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let Some(frame) = read_frame(&mut self.inner).await? else {
+            return Ok(None);
+        };
+        let mut buf = vec![0u8; frame.len()];
+        let len = self.transport.read_message(self.nonce, &frame, &mut buf).map_err(noise_error)?;
+        self.nonce += 1;
+        buf.truncate(len);
+        Ok(Some(buf))
+    }
+}
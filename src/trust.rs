@@ -0,0 +1,228 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use base64::engine::general_purpose;
+use base64::Engine;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::{oneshot, Mutex};
+use crate::identity::peer_id_from_public_key;
+use crate::peer::PeerId;
+use crate::storage;
+
+const TRUST_FILE_NAME: &str = "trusted_peers.json";
+
+/// What to do when a peer we have never paired with completes the handshake.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TrustPolicy {
+    /// Drop the connection.
+    Reject,
+    /// Ask the user to compare fingerprints and confirm.
+    #[default]
+    Prompt,
+    /// Let the peer sync without pairing.
+    Accept,
+}
+
+impl std::fmt::Display for TrustPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            TrustPolicy::Reject => "reject",
+            TrustPolicy::Prompt => "prompt",
+            TrustPolicy::Accept => "accept",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TrustedPeer {
+    pub public_key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+/// Persistent set of peers we have paired with, keyed by peer id, in the
+/// spirit of SSH's `known_hosts`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TrustStore {
+    #[serde(default)]
+    pub policy: TrustPolicy,
+    #[serde(default)]
+    pub peers: BTreeMap<String, TrustedPeer>,
+    /// Set from the config file or command line; wins over `policy` without
+    /// being saved.
+    #[serde(skip)]
+    pub policy_override: Option<TrustPolicy>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+pub type SharedTrustStore = Arc<Mutex<TrustStore>>;
+
+impl TrustStore {
+    pub fn path_in(data_dir: &Path) -> PathBuf {
+        data_dir.join(TRUST_FILE_NAME)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut store = match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid trust store: {}", e)))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => TrustStore::default(),
+            Err(e) => return Err(e),
+        };
+        store.path = path.to_path_buf();
+        Ok(store)
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let contents = serde_json::to_string_pretty(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        storage::write_atomic(&self.path, contents.as_bytes())
+    }
+
+    /// Replaces the policy and peers with what is on disk now.
+    fn reload(&mut self) -> io::Result<()> {
+        let saved = TrustStore::load(&self.path)?;
+        self.policy = saved.policy;
+        self.peers = saved.peers;
+        Ok(())
+    }
+
+    /// Picks up changes other processes (such as `peers trust` and `peers
+    /// revoke`) made to the file. Keeps the current contents if it can't be
+    /// read.
+    pub fn refresh(&mut self) {
+        let result = storage::lock(&self.path).and_then(|_lock| self.reload());
+        if let Err(e) = result {
+            eprintln!("Failed to reload trusted peers: {}", e);
+        }
+    }
+
+    /// Applies `change` to the store as it is on disk and saves it, holding
+    /// the file lock throughout so no other process's change is lost.
+    pub fn update<T>(&mut self, change: impl FnOnce(&mut TrustStore) -> T) -> io::Result<T> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let _lock = storage::lock(&self.path)?;
+        self.reload()?;
+        let result = change(self);
+        self.save()?;
+        Ok(result)
+    }
+
+    pub fn effective_policy(&self) -> TrustPolicy {
+        self.policy_override.unwrap_or(self.policy)
+    }
+
+    pub fn is_trusted(&self, public_key: &[u8; 32]) -> bool {
+        let peer_id = peer_id_from_public_key(public_key);
+        self.peers
+            .get(&peer_id.id)
+            .is_some_and(|trusted| trusted.public_key == general_purpose::STANDARD.encode(public_key))
+    }
+
+    pub fn trust(&mut self, public_key: &[u8; 32], name: Option<String>) -> PeerId {
+        let peer_id = peer_id_from_public_key(public_key);
+        self.peers.insert(peer_id.id.clone(), TrustedPeer {
+            public_key: general_purpose::STANDARD.encode(public_key),
+            name,
+        });
+        peer_id
+    }
+
+    /// Removes the trusted peer whose id starts with `prefix`, provided the
+    /// prefix is unambiguous. Returns the removed peer id.
+    pub fn revoke(&mut self, prefix: &str) -> Result<String, String> {
+        let matches: Vec<String> = self.peers.keys().filter(|id| id.starts_with(prefix)).cloned().collect();
+        match matches.as_slice() {
+            [] => Err(format!("No trusted peer matches '{}'", prefix)),
+            [id] => {
+                self.peers.remove(id);
+                Ok(id.clone())
+            }
+            _ => Err(format!("'{}' matches {} trusted peers; use a longer prefix", prefix, matches.len())),
+        }
+    }
+}
+
+/// Short, human-comparable digest of a public key, shown on both sides
+/// during pairing.
+pub fn fingerprint(public_key: &[u8; 32]) -> String {
+    let hash = Sha256::digest(public_key);
+    hash[..8]
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+pub fn decode_public_key(encoded: &str) -> Result<[u8; 32], String> {
+    general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()
+        .and_then(|b| b.try_into().ok())
+        .ok_or_else(|| "Public key must be 32 bytes of base64".to_string())
+}
+
+/// An unknown peer waiting for the user to confirm its fingerprint. The
+/// connection stays parked until `respond` is called or it times out.
+pub struct PairingRequest {
+    pub peer_id: PeerId,
+    pub public_key: [u8; 32],
+    pub address: SocketAddr,
+    responder: oneshot::Sender<bool>,
+}
+
+impl PairingRequest {
+    pub fn new(peer_id: PeerId, public_key: [u8; 32], address: SocketAddr) -> (Self, oneshot::Receiver<bool>) {
+        let (responder, decision) = oneshot::channel();
+        (PairingRequest { peer_id, public_key, address, responder }, decision)
+    }
+
+    /// False once the connection that asked has given up waiting.
+    pub fn is_pending(&self) -> bool {
+        !self.responder.is_closed()
+    }
+
+    pub fn respond(self, accept: bool) {
+        let _ = self.responder.send(accept);
+    }
+}
+
+pub type PairingRequests = Arc<Mutex<Vec<PairingRequest>>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::ScratchDir;
+
+    #[test]
+    fn updates_merge_with_changes_from_other_processes() {
+        let dir = ScratchDir::new("trust");
+        let path = dir.join(TRUST_FILE_NAME);
+        let (old_key, new_key) = ([1u8; 32], [2u8; 32]);
+        let mut cli = TrustStore::load(&path).unwrap();
+        cli.update(|store| store.trust(&old_key, None)).unwrap();
+
+        // A long-running process loaded the store before the revoke.
+        let mut daemon = TrustStore::load(&path).unwrap();
+        let old_id = peer_id_from_public_key(&old_key).id;
+        cli.update(|store| store.revoke(&old_id)).unwrap().unwrap();
+
+        daemon.refresh();
+        assert!(!daemon.is_trusted(&old_key));
+        daemon.update(|store| store.trust(&new_key, None)).unwrap();
+
+        let saved = TrustStore::load(&path).unwrap();
+        assert!(!saved.is_trusted(&old_key));
+        assert!(saved.is_trusted(&new_key));
+    }
+}
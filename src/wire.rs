@@ -0,0 +1,194 @@
+use std::fmt;
+use crate::network::Message;
+
+/// Version 1 is newline-delimited JSON, which peers that predate versioning
+/// speak (their `Hello` carries no version). Version 2 adds binary frames.
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Capability advertised in the `Hello` by peers that accept binary frames.
+pub const BINARY_FRAMES: &str = "binary-frames";
+/// Largest message either encoding may carry.
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+const LENGTH_PREFIX: usize = 4;
+
+/// How messages are laid out on an encrypted channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    /// One JSON object per line, as every version understands.
+    JsonLines,
+    /// A big-endian `u32` length followed by a MessagePack body, with change
+    /// bytes as raw binary rather than arrays of numbers.
+    Binary,
+}
+
+impl Encoding {
+    /// The encoding to use after exchanging `Hello`s: binary if both sides
+    /// offer it, JSON lines otherwise.
+    pub fn negotiate(remote_version: u32, remote_capabilities: &[String]) -> Self {
+        if remote_version >= 2 && remote_capabilities.iter().any(|capability| capability == BINARY_FRAMES) {
+            Encoding::Binary
+        } else {
+            Encoding::JsonLines
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WireError {
+    TooLarge(usize),
+    Encode(String),
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireError::TooLarge(size) => write!(f, "message of {} bytes exceeds the {} byte limit", size, MAX_FRAME_SIZE),
+            WireError::Encode(e) => write!(f, "failed to encode message: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for WireError {}
+
+/// The capabilities this build offers in its `Hello`.
+pub fn capabilities() -> Vec<String> {
+    vec![BINARY_FRAMES.to_string()]
+}
+
+pub fn encode(msg: &Message, encoding: Encoding) -> Result<Vec<u8>, WireError> {
+    let check_size = |len: usize| if len > MAX_FRAME_SIZE { Err(WireError::TooLarge(len)) } else { Ok(()) };
+    match encoding {
+        Encoding::JsonLines => {
+            let mut out = serde_json::to_vec(msg).map_err(|e| WireError::Encode(e.to_string()))?;
+            check_size(out.len())?;
+            out.push(b'\n');
+            Ok(out)
+        }
+        Encoding::Binary => {
+            let body = rmp_serde::to_vec_named(msg).map_err(|e| WireError::Encode(e.to_string()))?;
+            check_size(body.len())?;
+            let mut out = Vec::with_capacity(LENGTH_PREFIX + body.len());
+            out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+            out.extend_from_slice(&body);
+            Ok(out)
+        }
+    }
+}
+
+/// Reassembles messages from the chunks an encrypted channel delivers. The
+/// encoding can change between messages, once the `Hello`s are exchanged.
+pub struct Decoder {
+    buffer: Vec<u8>,
+    pub encoding: Encoding,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder { buffer: Vec::new(), encoding: Encoding::JsonLines }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// The next complete message, if one has arrived. Messages that don't
+    /// parse, such as kinds added by a newer version, are skipped; a frame
+    /// over the size limit is an error.
+    pub fn next_message(&mut self) -> Result<Option<Message>, WireError> {
+        loop {
+            match self.encoding {
+                Encoding::JsonLines => {
+                    let Some(pos) = self.buffer.iter().position(|&b| b == b'\n') else {
+                        if self.buffer.len() > MAX_FRAME_SIZE {
+                            return Err(WireError::TooLarge(self.buffer.len()));
+                        }
+                        return Ok(None);
+                    };
+                    let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+                    if let Ok(msg) = serde_json::from_slice::<Message>(&line[..pos]) {
+                        return Ok(Some(msg));
+                    }
+                }
+                Encoding::Binary => {
+                    let Some(prefix) = self.buffer.get(..LENGTH_PREFIX) else { return Ok(None) };
+                    let len = u32::from_be_bytes(prefix.try_into().expect("prefix is four bytes")) as usize;
+                    if len > MAX_FRAME_SIZE {
+                        return Err(WireError::TooLarge(len));
+                    }
+                    if self.buffer.len() < LENGTH_PREFIX + len {
+                        return Ok(None);
+                    }
+                    let frame: Vec<u8> = self.buffer.drain(..LENGTH_PREFIX + len).skip(LENGTH_PREFIX).collect();
+                    if let Ok(msg) = rmp_serde::from_slice::<Message>(&frame) {
+                        return Ok(Some(msg));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello() -> Message {
+        Message::Hello {
+            peer_id: "peer".to_string(),
+            public_key: String::new(),
+            nonce: String::new(),
+            version: PROTOCOL_VERSION,
+            capabilities: capabilities(),
+        }
+    }
+
+    #[test]
+    fn switches_encoding_within_one_chunk() {
+        let mut chunk = encode(&hello(), Encoding::JsonLines).unwrap();
+        chunk.extend(encode(&Message::Auth { signature: "sig".to_string() }, Encoding::Binary).unwrap());
+        let mut decoder = Decoder::new();
+        decoder.push(&chunk);
+
+        assert!(matches!(decoder.next_message().unwrap(), Some(Message::Hello { .. })));
+        decoder.encoding = Encoding::Binary;
+        assert!(matches!(decoder.next_message().unwrap(), Some(Message::Auth { signature }) if signature == "sig"));
+        assert!(decoder.next_message().unwrap().is_none());
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_a_frame() {
+        let frame = encode(&Message::Sync { list: "default".to_string(), data: vec![1, 2, 3] }, Encoding::Binary).unwrap();
+        let mut decoder = Decoder::new();
+        decoder.encoding = Encoding::Binary;
+        decoder.push(&frame[..frame.len() - 1]);
+        assert!(decoder.next_message().unwrap().is_none());
+        decoder.push(&frame[frame.len() - 1..]);
+        assert!(matches!(decoder.next_message().unwrap(), Some(Message::Sync { data, .. }) if data == [1, 2, 3]));
+    }
+
+    #[test]
+    fn rejects_oversize_frames() {
+        let mut decoder = Decoder::new();
+        decoder.encoding = Encoding::Binary;
+        decoder.push(&(MAX_FRAME_SIZE as u32 + 1).to_be_bytes());
+        assert!(matches!(decoder.next_message(), Err(WireError::TooLarge(size)) if size == MAX_FRAME_SIZE + 1));
+
+        let mut decoder = Decoder::new();
+        decoder.push(&vec![b' '; MAX_FRAME_SIZE + 1]);
+        assert!(matches!(decoder.next_message(), Err(WireError::TooLarge(_))));
+    }
+
+    #[test]
+    fn skips_unknown_messages() {
+        let mut decoder = Decoder::new();
+        decoder.push(b"{\"type\":\"FromTheFuture\",\"data\":{}}\n");
+        decoder.push(&encode(&Message::Ping { id: 1 }, Encoding::JsonLines).unwrap());
+        assert!(matches!(decoder.next_message().unwrap(), Some(Message::Ping { id: 1 })));
+
+        let unknown = rmp_serde::to_vec_named(&("FromTheFuture", 7)).unwrap();
+        decoder.encoding = Encoding::Binary;
+        decoder.push(&(unknown.len() as u32).to_be_bytes());
+        decoder.push(&unknown);
+        decoder.push(&encode(&Message::Pong { id: 2 }, Encoding::Binary).unwrap());
+        assert!(matches!(decoder.next_message().unwrap(), Some(Message::Pong { id: 2 })));
+    }
+}
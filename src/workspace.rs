@@ -0,0 +1,375 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::crdt::CrdtToDoList;
+use crate::storage;
+use crate::tasks::new_task_id;
+
+/// Key of the list that lives in the original single-list document, so
+/// existing data and peers keep working.
+pub const DEFAULT_LIST_KEY: &str = "default";
+const DEFAULT_LIST_FILE: &str = "autocommit_doc.automerge";
+const LISTS_DIR: &str = "lists";
+const WORKSPACE_FILE: &str = "lists.json";
+
+/// Where data lives unless `--data-dir` or `RUSTYTASKS_HOME` say otherwise:
+/// `<data dir>/rustytasks` (e.g. `~/.local/share/rustytasks` on Linux),
+/// falling back to the current directory if no data directory is known.
+pub fn default_data_dir() -> PathBuf {
+    dirs::data_dir()
+        .map(|dir| dir.join("rustytasks"))
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+/// The document file of the default list inside `data_dir`.
+pub fn default_list_file(data_dir: &Path) -> PathBuf {
+    data_dir.join(DEFAULT_LIST_FILE)
+}
+
+/// Which lists are synced with a peer.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ShareScope {
+    #[default]
+    All,
+    /// Only these list keys.
+    Only(BTreeSet<String>),
+}
+
+impl ShareScope {
+    pub fn includes(&self, list_key: &str) -> bool {
+        match self {
+            ShareScope::All => true,
+            ShareScope::Only(keys) => keys.contains(list_key),
+        }
+    }
+}
+
+/// Local, unsynced settings of a workspace.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+struct WorkspaceState {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    current: Option<String>,
+    /// Sharing per peer id; peers not listed share every list.
+    #[serde(default)]
+    sharing: BTreeMap<String, ShareScope>,
+    /// Lists deleted here, so a peer that still has them can't bring them back.
+    #[serde(default)]
+    deleted: BTreeSet<String>,
+}
+
+/// A set of named task lists. Each list is a separate Automerge document, so
+/// it can be shared with some peers and not others.
+pub struct Workspace {
+    dir: PathBuf,
+    default_file: PathBuf,
+    state: WorkspaceState,
+    /// `state` as last read from or written to disk, to tell our changes
+    /// from those another process saved in the meantime.
+    saved_state: WorkspaceState,
+    lists: BTreeMap<String, CrdtToDoList>,
+    /// The list commands act on; starts as the last one switched to.
+    current: String,
+    auto_complete_parents: bool,
+}
+
+pub struct ListSummary {
+    pub key: String,
+    pub name: String,
+    pub tasks: usize,
+    pub current: bool,
+}
+
+impl Workspace {
+    /// Opens the workspace stored in `dir`. The default list is kept in
+    /// `default_file` (normally `autocommit_doc.automerge` in `dir`); other
+    /// lists are kept under `lists/`.
+    pub fn open(dir: &Path, default_file: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let state: WorkspaceState = match fs::read_to_string(dir.join(WORKSPACE_FILE)) {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => WorkspaceState::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut lists = BTreeMap::new();
+        let default = CrdtToDoList::new(DEFAULT_LIST_KEY, Some(default_file))?;
+        lists.insert(DEFAULT_LIST_KEY.to_string(), default);
+
+        match fs::read_dir(dir.join(LISTS_DIR)) {
+            Ok(entries) => {
+                for entry in entries {
+                    // A list not compacted yet only has its change log.
+                    let path = entry?.path();
+                    let Some(name) = path.file_name().and_then(|name| name.to_str()) else { continue };
+                    let Some(key) = name.strip_suffix(".automerge").or_else(|| name.strip_suffix(".automerge.log")) else { continue };
+                    if !lists.contains_key(key) && !state.deleted.contains(key) {
+                        let path = path.with_file_name(format!("{}.automerge", key));
+                        lists.insert(key.to_string(), CrdtToDoList::new(key, Some(&path))?);
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let current = state
+            .current
+            .clone()
+            .filter(|key| lists.contains_key(key))
+            .unwrap_or_else(|| DEFAULT_LIST_KEY.to_string());
+
+        Ok(Workspace {
+            dir: dir.to_path_buf(),
+            default_file: default_file.to_path_buf(),
+            saved_state: state.clone(),
+            state,
+            lists,
+            current,
+            auto_complete_parents: false,
+        })
+    }
+
+    fn path_for(&self, key: &str) -> PathBuf {
+        if key == DEFAULT_LIST_KEY {
+            self.default_file.clone()
+        } else {
+            self.dir.join(LISTS_DIR).join(format!("{}.automerge", key))
+        }
+    }
+
+    /// Saves every list and the workspace settings, merging in whatever
+    /// other processes saved since we opened the workspace.
+    pub fn save(&mut self) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        if let Some(parent) = self.default_file.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        if self.lists.len() > 1 {
+            fs::create_dir_all(self.dir.join(LISTS_DIR))?;
+        }
+        let state_path = self.dir.join(WORKSPACE_FILE);
+        let _lock = storage::lock(&state_path)?;
+        self.merge_saved_state(&state_path)?;
+
+        let paths: Vec<(String, PathBuf)> = self.lists.keys().map(|key| (key.clone(), self.path_for(key))).collect();
+        for (key, path) in paths {
+            if let Some(list) = self.lists.get_mut(&key) {
+                list.save_to_file(&path)?;
+            }
+        }
+        let contents = serde_json::to_string_pretty(&self.state)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        storage::write_atomic(&state_path, contents.as_bytes())?;
+        self.saved_state = self.state.clone();
+        Ok(())
+    }
+
+    /// Folds in the settings saved at `path` by another process. Settings
+    /// changed here since the last save win; lists deleted there are dropped.
+    fn merge_saved_state(&mut self, path: &Path) -> io::Result<()> {
+        let Some(contents) = storage::read_if_exists(path)? else { return Ok(()) };
+        let on_disk: WorkspaceState = serde_json::from_slice(&contents)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        if self.state.current == self.saved_state.current {
+            self.state.current = on_disk.current.clone();
+        }
+        let peers: BTreeSet<String> = on_disk.sharing.keys().chain(self.saved_state.sharing.keys()).cloned().collect();
+        for peer in peers {
+            if self.state.sharing.get(&peer) != self.saved_state.sharing.get(&peer) {
+                continue;
+            }
+            match on_disk.sharing.get(&peer) {
+                Some(scope) => self.state.sharing.insert(peer, scope.clone()),
+                None => self.state.sharing.remove(&peer),
+            };
+        }
+        self.state.deleted.extend(on_disk.deleted);
+        self.lists.retain(|key, _| !self.state.deleted.contains(key));
+        if !self.lists.contains_key(&self.current) {
+            self.current = DEFAULT_LIST_KEY.to_string();
+        }
+        Ok(())
+    }
+
+    pub fn current(&self) -> &CrdtToDoList {
+        &self.lists[&self.current]
+    }
+
+    pub fn current_mut(&mut self) -> &mut CrdtToDoList {
+        self.lists.get_mut(&self.current).expect("current list is always loaded")
+    }
+
+    pub fn current_name(&self) -> String {
+        self.display_name(&self.current)
+    }
+
+    fn display_name(&self, key: &str) -> String {
+        self.lists
+            .get(key)
+            .and_then(|list| list.name())
+            .unwrap_or_else(|| key.to_string())
+    }
+
+    /// Finds a list by exact name, or by a unique prefix of its key.
+    pub fn resolve(&self, query: &str) -> Result<String, String> {
+        let query = query.trim();
+        let by_name: Vec<&String> = self.lists.keys().filter(|key| self.display_name(key) == query).collect();
+        if let [key] = by_name.as_slice() {
+            return Ok((*key).clone());
+        }
+        if by_name.len() > 1 {
+            return Err(format!("'{}' names {} lists; use the list key instead", query, by_name.len()));
+        }
+
+        let by_key: Vec<&String> = self.lists.keys().filter(|key| key.starts_with(query)).collect();
+        match by_key.as_slice() {
+            [key] if !query.is_empty() => Ok((*key).clone()),
+            [] | [_] => Err(format!("No list named '{}'", query)),
+            _ => Err(format!("'{}' matches {} lists", query, by_key.len())),
+        }
+    }
+
+    /// Makes `query` the current list for this run only.
+    pub fn select(&mut self, query: &str) -> Result<(), String> {
+        self.current = self.resolve(query)?;
+        Ok(())
+    }
+
+    /// Goes back to the remembered current list after `select`.
+    pub fn reset_current(&mut self) {
+        self.current = self
+            .state
+            .current
+            .clone()
+            .filter(|key| self.lists.contains_key(key))
+            .unwrap_or_else(|| DEFAULT_LIST_KEY.to_string());
+    }
+
+    /// Makes `query` the current list, remembered across runs.
+    pub fn switch(&mut self, query: &str) -> Result<(), String> {
+        self.select(query)?;
+        self.state.current = Some(self.current.clone());
+        Ok(())
+    }
+
+    pub fn create(&mut self, name: &str) -> Result<String, String> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err("A list needs a name".to_string());
+        }
+        if self.lists.keys().any(|key| self.display_name(key) == name) {
+            return Err(format!("A list named '{}' already exists", name));
+        }
+
+        let key = new_task_id();
+        let mut list = CrdtToDoList::new(&key, None).map_err(|e| e.to_string())?;
+        list.set_name(name).map_err(|e| e.to_string())?;
+        list.auto_complete_parents = self.auto_complete_parents;
+        self.lists.insert(key.clone(), list);
+        Ok(key)
+    }
+
+    pub fn rename(&mut self, query: &str, name: &str) -> Result<(), String> {
+        let key = self.resolve(query)?;
+        let name = name.trim();
+        if name.is_empty() {
+            return Err("A list needs a name".to_string());
+        }
+        if self.lists.keys().any(|other| *other != key && self.display_name(other) == name) {
+            return Err(format!("A list named '{}' already exists", name));
+        }
+        self.lists.get_mut(&key).expect("resolved list exists").set_name(name).map_err(|e| e.to_string())
+    }
+
+    /// Deletes a list locally. Peers keep their copies.
+    pub fn delete(&mut self, query: &str) -> Result<String, String> {
+        let key = self.resolve(query)?;
+        if key == DEFAULT_LIST_KEY {
+            return Err("The default list can't be deleted".to_string());
+        }
+        let name = self.display_name(&key);
+        let path = self.path_for(&key);
+        for file in [storage::log_path(&path), path] {
+            match fs::remove_file(file) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        self.lists.remove(&key);
+        self.state.deleted.insert(key.clone());
+        if self.current == key {
+            self.current = DEFAULT_LIST_KEY.to_string();
+        }
+        if self.state.current.as_deref() == Some(key.as_str()) {
+            self.state.current = None;
+        }
+        Ok(name)
+    }
+
+    /// Every list, the default one first and the rest by name.
+    pub fn summaries(&self) -> Vec<ListSummary> {
+        let mut summaries: Vec<ListSummary> = self
+            .lists
+            .iter()
+            .map(|(key, list)| ListSummary {
+                key: key.clone(),
+                name: self.display_name(key),
+                tasks: list.task_entries.len(),
+                current: *key == self.current,
+            })
+            .collect();
+        summaries.sort_by_key(|list| (list.key != DEFAULT_LIST_KEY, list.name.to_lowercase()));
+        summaries
+    }
+
+    /// The heads of every list, to tell whether anything changed since
+    /// they were last taken.
+    pub fn heads(&mut self) -> Vec<(String, Vec<automerge::ChangeHash>)> {
+        self.lists.iter_mut().map(|(key, list)| (key.clone(), list.heads())).collect()
+    }
+
+    pub fn list_keys(&self) -> Vec<String> {
+        self.lists.keys().cloned().collect()
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut CrdtToDoList> {
+        self.lists.get_mut(key)
+    }
+
+    /// The list a peer is sending changes for, creating it if the peer is
+    /// the first to share it with us. Lists deleted here stay deleted.
+    pub fn list_for_sync(&mut self, key: &str) -> Option<&mut CrdtToDoList> {
+        if self.state.deleted.contains(key) || key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return None;
+        }
+        if !self.lists.contains_key(key) {
+            let mut list = CrdtToDoList::new(key, None).ok()?;
+            list.auto_complete_parents = self.auto_complete_parents;
+            self.lists.insert(key.to_string(), list);
+        }
+        self.lists.get_mut(key)
+    }
+
+    pub fn set_auto_complete_parents(&mut self, enabled: bool) {
+        self.auto_complete_parents = enabled;
+        for list in self.lists.values_mut() {
+            list.auto_complete_parents = enabled;
+        }
+    }
+
+    pub fn scope_for(&self, peer_id: &str) -> ShareScope {
+        self.state.sharing.get(peer_id).cloned().unwrap_or_default()
+    }
+
+    pub fn share(&mut self, peer_id: &str, scope: ShareScope) {
+        if scope == ShareScope::All {
+            self.state.sharing.remove(peer_id);
+        } else {
+            self.state.sharing.insert(peer_id.to_string(), scope);
+        }
+    }
+}